@@ -0,0 +1,52 @@
+use crate::commands::sync::apply_plan;
+use crate::config::ResolvedConfig;
+use crate::gitx::{GitRepo, OpenRepoOpts};
+use crate::plan::SyncPlan;
+use crate::report::Report;
+use crate::util::print_plan;
+use anyhow::{Context, Result};
+use tracing::{info, warn};
+
+pub struct ApplyArgs {
+    pub dry_run: bool,
+    pub plan_path: String,
+    pub config_path: Option<String>,
+}
+
+pub fn run_apply(args: ApplyArgs, report: &mut Report) -> Result<()> {
+    let repo = GitRepo::discover(OpenRepoOpts {
+        workdir: ".".into(),
+    })?;
+    let text = std::fs::read_to_string(&args.plan_path)
+        .with_context(|| format!("read plan {}", args.plan_path))?;
+    let plan: SyncPlan =
+        serde_json::from_str(&text).with_context(|| format!("parse plan {}", args.plan_path))?;
+    let config = ResolvedConfig::load(args.config_path.as_deref(), &repo, None)?;
+    report.config = Some(config.clone());
+
+    print_plan(&plan);
+
+    report.plan = Some(plan.clone());
+
+    if repo.rebase_in_progress() || repo.merge_in_progress() {
+        anyhow::bail!(
+            "a sync is stopped on conflicts; run `git-helper sync --continue`, `--skip` or `--abort` first"
+        );
+    }
+    let drift = plan.drift(&repo.head_state()?, |name| {
+        repo.ref_target(name).ok().flatten()
+    });
+    if !drift.is_empty() {
+        for line in &drift {
+            warn!("{line}");
+        }
+        anyhow::bail!("repository has changed since the plan was made; re-run `sync --plan-out`");
+    }
+
+    if !args.dry_run {
+        apply_plan(&repo, &config, &plan, report)?;
+    } else {
+        info!("dry-run: no changes applied");
+    }
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use crate::config::ResolvedConfig;
+use crate::gitx::{Backup, GitRepo, OpenRepoOpts};
+use crate::plan::short;
+use crate::report::{BackupState, Report};
+use anyhow::Result;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+pub struct BackupsArgs {
+    pub dry_run: bool,
+    pub config_path: Option<String>,
+    pub action: BackupsAction,
+}
+
+pub enum BackupsAction {
+    /// Every backup, or only those of `branch`.
+    List { branch: Option<String> },
+    /// Move a branch back to a backed-up tip: a full ref name,
+    /// `<time>/<branch>`, or a branch for its newest backup.
+    Restore { backup: String },
+    /// Delete the backups the configured expiry no longer keeps.
+    Prune,
+}
+
+pub fn run_backups(args: BackupsArgs, report: &mut Report) -> Result<()> {
+    let repo = GitRepo::discover(OpenRepoOpts {
+        workdir: ".".into(),
+    })?;
+
+    match args.action {
+        BackupsAction::List { branch } => {
+            let backups: Vec<Backup> = repo
+                .backups()?
+                .into_iter()
+                .filter(|backup| branch.as_ref().is_none_or(|b| *b == backup.branch))
+                .collect();
+            report.backups = Some(backups.iter().map(BackupState::from).collect());
+            if backups.is_empty() {
+                info!("no backups");
+                return Ok(());
+            }
+            let lines = backups
+                .iter()
+                .map(|backup| describe(&repo, backup))
+                .collect::<Result<Vec<_>>>()?;
+            info!("backups, oldest first:\n{}", lines.join("\n"));
+        }
+        BackupsAction::Restore { backup } => {
+            if repo.rebase_in_progress() || repo.merge_in_progress() {
+                anyhow::bail!(
+                    "a sync is stopped on conflicts; run `git-helper sync --abort` first"
+                );
+            }
+            let backup = repo.find_backup(&backup)?;
+            if args.dry_run {
+                info!(
+                    "dry-run: would reset {} to {} from {}",
+                    backup.branch,
+                    short(&backup.tip),
+                    backup.name
+                );
+                return Ok(());
+            }
+            match repo.restore_backup(&backup)? {
+                Some(saved) => info!(
+                    "{} reset to {}; where it was is saved as {saved}",
+                    backup.branch,
+                    short(&backup.tip)
+                ),
+                None => info!("{} already at {}", backup.branch, short(&backup.tip)),
+            }
+        }
+        BackupsAction::Prune => {
+            let config = ResolvedConfig::load(args.config_path.as_deref(), &repo, None)?;
+            let expired = repo.expired_backups(config.backup_expiry())?;
+            report.config = Some(config);
+            report.backups = Some(expired.iter().map(BackupState::from).collect());
+            if expired.is_empty() {
+                info!("no backups have expired");
+                return Ok(());
+            }
+            let lines = expired
+                .iter()
+                .map(|backup| describe(&repo, backup))
+                .collect::<Result<Vec<_>>>()?;
+            if args.dry_run {
+                info!("dry-run: would delete:\n{}", lines.join("\n"));
+                return Ok(());
+            }
+            for backup in &expired {
+                repo.delete_backup(backup)?;
+            }
+            info!("deleted:\n{}", lines.join("\n"));
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the backups `config` no longer keeps, naming each one. Runs
+/// before every applied plan, so backups don't pile up without anyone
+/// running `backups prune`.
+pub fn expire_backups(repo: &GitRepo, config: &ResolvedConfig) -> Result<()> {
+    for backup in repo.expired_backups(config.backup_expiry())? {
+        repo.delete_backup(&backup)?;
+        info!(
+            "expired backup {}/{} ({})",
+            backup.time,
+            backup.branch,
+            short(&backup.tip)
+        );
+    }
+    Ok(())
+}
+
+/// `<time>/<branch>  <tip> <summary> (<age>)`, as `backups restore` takes it.
+fn describe(repo: &GitRepo, backup: &Backup) -> Result<String> {
+    let summary = match repo.inner.find_commit(backup.tip) {
+        Ok(commit) => commit.summary().unwrap_or_default().to_string(),
+        Err(_) => "(commit missing)".to_string(),
+    };
+    Ok(format!(
+        "{}/{}  {} {summary} ({})",
+        backup.time,
+        backup.branch,
+        short(&backup.tip),
+        age(backup.time)
+    ))
+}
+
+/// How long ago `time` (seconds since the Unix epoch) was, roughly.
+fn age(time: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(time, |now| now.as_secs());
+    let (count, unit) = match now.saturating_sub(time) {
+        secs if secs < 60 => return "just now".to_string(),
+        secs if secs < 60 * 60 => (secs / 60, "minute"),
+        secs if secs < 24 * 60 * 60 => (secs / (60 * 60), "hour"),
+        secs => (secs / (24 * 60 * 60), "day"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{count} {unit}{plural} ago")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitx::test_support::TestRepo;
+    use git2::Repository;
+
+    #[test]
+    fn test_expire_backups_keeps_what_config_allows() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let tip = test_repo.commit_file("file.txt", "one\n", "one")?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        for (time, branch) in [(now - 30, "main"), (now - 20, "main"), (now - 10, "other")] {
+            test_repo.repo.reference(
+                &format!("refs/git-helper/backup/{time}/{branch}"),
+                tip,
+                false,
+                "test",
+            )?;
+        }
+        let repo = GitRepo {
+            inner: Repository::open(test_repo.path())?,
+        };
+        let mut config = ResolvedConfig::from_layers(&[], &repo, None)?;
+        config.backup_max_count = 1;
+
+        expire_backups(&repo, &config)?;
+
+        let left: Vec<_> = repo
+            .backups()?
+            .into_iter()
+            .map(|b| (b.time, b.branch))
+            .collect();
+        assert_eq!(
+            left,
+            [
+                (now - 20, "main".to_string()),
+                (now - 10, "other".to_string())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_age() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert_eq!(age(now), "just now");
+        assert_eq!(age(now - 2 * 60 * 60 - 5), "2 hours ago");
+        assert_eq!(age(now - 24 * 60 * 60 - 5), "1 day ago");
+    }
+}
@@ -0,0 +1,214 @@
+use crate::commands::sync::apply_plan;
+use crate::config::ResolvedConfig;
+use crate::gitx::{BranchStatus, GitRepo, OpenRepoOpts, PatchIds};
+use crate::plan::{DeleteReason, SyncOp, SyncPlan};
+use crate::report::Report;
+use crate::util::print_plan;
+use anyhow::Result;
+use git2::Oid;
+use tracing::info;
+
+pub struct CleanArgs {
+    pub dry_run: bool,
+    pub config_path: Option<String>,
+    /// Also delete branches whose upstream is gone although their commits
+    /// are nowhere else.
+    pub force: bool,
+}
+
+pub fn run_clean(args: CleanArgs, report: &mut Report) -> Result<()> {
+    let repo = GitRepo::discover(OpenRepoOpts {
+        workdir: ".".into(),
+    })?;
+    let config = ResolvedConfig::load(args.config_path.as_deref(), &repo, None)?;
+    report.config = Some(config.clone());
+
+    // Judge merges against whichever of main and its remote is further along.
+    let main_tip = match repo.branch_status(&config.main, &config.remote)? {
+        BranchStatus::Behind | BranchStatus::MissingLocal => {
+            repo.remote_tip(&config.main, &config.remote)?
+        }
+        _ => repo.local_tip(&config.main)?,
+    };
+    let current = repo.current_branch_name()?;
+
+    let mut plan = SyncPlan::new();
+    plan_cleanup(&repo, &config, main_tip, &current, args.force, &mut plan)?;
+
+    print_plan(&plan);
+
+    report.plan = Some(plan.clone());
+
+    if !args.dry_run {
+        apply_plan(&repo, &config, &plan, report)?;
+    } else {
+        info!("dry-run: no changes applied");
+    }
+    Ok(())
+}
+
+/// Adds a `DeleteBranch` for every local branch that is merged or
+/// squash-merged into `main_tip`, or whose upstream was pruned. Main, the
+/// current branch and branches other ops in `plan` touch are kept, and so
+/// are gone branches with commits neither main nor the remote has, unless
+/// `force`.
+pub fn plan_cleanup(
+    repo: &GitRepo,
+    config: &ResolvedConfig,
+    main_tip: Oid,
+    current: &str,
+    force: bool,
+    plan: &mut SyncPlan,
+) -> Result<()> {
+    let mut tracked = vec![];
+    for remote in config.remotes() {
+        for branch in repo.tracked_branches(remote)? {
+            tracked.push((branch, remote));
+        }
+    }
+    let touched = plan.touched_refs();
+    let mut main_ids = PatchIds::default();
+    for (refname, tip) in repo.local_branch_tips()? {
+        let Some(branch) = refname.strip_prefix("refs/heads/") else {
+            continue;
+        };
+        // A branch sitting exactly on main was most likely just created.
+        if branch == config.main
+            || branch == current
+            || tip == main_tip
+            || touched.contains(&refname)
+        {
+            continue;
+        }
+
+        let reason = if repo.is_merged(tip, main_tip)? {
+            DeleteReason::Merged {
+                into: config.main.clone(),
+            }
+        } else if repo.is_squash_merged(tip, main_tip, &mut main_ids)? {
+            DeleteReason::SquashMerged {
+                into: config.main.clone(),
+            }
+        } else if let Some((_, remote)) = tracked.iter().find(|(t, _)| t == branch)
+            && repo.branch_status(branch, remote)? == BranchStatus::MissingRemote
+        {
+            // Only the remote branch is gone; its commits may not be.
+            if !force && !repo.is_on_remote(tip, remote)? {
+                let unmerged = repo.local_only(main_tip, tip)?.len();
+                plan.notes.push(format!(
+                    "{branch}: upstream gone, but {unmerged} commit(s) are not in {}; \
+                     kept (`clean --force` deletes it)",
+                    config.main
+                ));
+                continue;
+            }
+            DeleteReason::UpstreamGone
+        } else {
+            continue;
+        };
+
+        if repo.checked_out_elsewhere(branch)? {
+            plan.notes.push(format!(
+                "{branch} is {reason} but checked out in another worktree; kept"
+            ));
+            continue;
+        }
+        plan.push(SyncOp::DeleteBranch {
+            branch: branch.to_string(),
+            reason,
+            tip,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitx::test_support::TestRepo;
+    use git2::Repository;
+
+    /// A branch off main with one commit of its own, tracking a pruned
+    /// `origin/<name>`.
+    fn gone_branch(test_repo: &TestRepo, name: &str) -> Result<Oid> {
+        test_repo.branch(name)?;
+        test_repo.checkout(name)?;
+        let tip = test_repo.commit_file(&format!("{name}.txt"), "work\n", name)?;
+        test_repo.checkout("main")?;
+        let mut config = test_repo.repo.config()?;
+        config.set_str(&format!("branch.{name}.remote"), "origin")?;
+        config.set_str(
+            &format!("branch.{name}.merge"),
+            &format!("refs/heads/{name}"),
+        )?;
+        Ok(tip)
+    }
+
+    #[test]
+    fn test_gone_branches_with_unpushed_commits_are_kept() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let main_tip = test_repo.commit_file("base.txt", "base\n", "base")?;
+        test_repo.repo.remote("origin", "/nonexistent")?;
+        let pushed = gone_branch(&test_repo, "pushed")?;
+        gone_branch(&test_repo, "unpushed")?;
+        // `pushed` made it into another branch on the remote.
+        test_repo
+            .repo
+            .reference("refs/remotes/origin/release", pushed, true, "fetch")?;
+        let repo = GitRepo {
+            inner: Repository::open(test_repo.path())?,
+        };
+        let config = ResolvedConfig::from_layers(&[], &repo, None)?;
+        let deleted = |plan: &SyncPlan| -> Vec<String> {
+            plan.ops
+                .iter()
+                .filter_map(|op| match op {
+                    SyncOp::DeleteBranch { branch, .. } => Some(branch.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let mut plan = SyncPlan::new();
+        plan_cleanup(&repo, &config, main_tip, "main", false, &mut plan)?;
+        assert_eq!(deleted(&plan), ["pushed"]);
+        assert_eq!(
+            plan.notes,
+            ["unpushed: upstream gone, but 1 commit(s) are not in main; \
+              kept (`clean --force` deletes it)"]
+        );
+
+        let mut plan = SyncPlan::new();
+        plan_cleanup(&repo, &config, main_tip, "main", true, &mut plan)?;
+        assert_eq!(deleted(&plan), ["pushed", "unpushed"]);
+        assert!(plan.notes.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_orphan_branches_are_not_merged() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let main_tip = test_repo.commit_file("base.txt", "base\n", "base")?;
+        let signature = test_repo.repo.signature()?;
+        let tree = test_repo
+            .repo
+            .find_tree(test_repo.repo.treebuilder(None)?.write()?)?;
+        test_repo.repo.commit(
+            Some("refs/heads/gh-pages"),
+            &signature,
+            &signature,
+            "pages",
+            &tree,
+            &[],
+        )?;
+        let repo = GitRepo {
+            inner: Repository::open(test_repo.path())?,
+        };
+        let config = ResolvedConfig::from_layers(&[], &repo, None)?;
+
+        let mut plan = SyncPlan::new();
+        plan_cleanup(&repo, &config, main_tip, "main", false, &mut plan)?;
+        assert!(plan.ops.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+use crate::config::{ResolvedConfig, Scope, canonical_key, detect_fork, set_in_file};
+use crate::gitx::{GitRepo, OpenRepoOpts};
+use crate::report::{OutputFormat, Report, Setting};
+use anyhow::{Context, Result};
+use tracing::info;
+
+pub struct ConfigArgs {
+    pub dry_run: bool,
+    pub config_path: Option<String>,
+    pub action: ConfigAction,
+    pub format: OutputFormat,
+}
+
+pub enum ConfigAction {
+    Show,
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+        scope: Scope,
+    },
+    /// Write a commented starter file; refuses to overwrite unless `force`.
+    Init {
+        scope: Scope,
+        force: bool,
+    },
+}
+
+pub fn run_config(args: ConfigArgs, report: &mut Report) -> Result<()> {
+    let repo = GitRepo::discover(OpenRepoOpts {
+        workdir: ".".into(),
+    })?;
+
+    match args.action {
+        ConfigAction::Show => {
+            let config = ResolvedConfig::load(args.config_path.as_deref(), &repo, None)?;
+            let mut lines = vec![];
+            for (key, value) in config.settings()? {
+                let source = config.sources.get(&key).map_or("", String::as_str);
+                lines.push(format!("{key} = {value}  # {source}"));
+            }
+            info!("effective config:\n{}", lines.join("\n"));
+            report.config = Some(config);
+        }
+        ConfigAction::Get { key } => {
+            let config = ResolvedConfig::load(args.config_path.as_deref(), &repo, None)?;
+            let settings = config.settings()?;
+            let key = canonical_key(&key)?;
+            let (_, value) = settings
+                .iter()
+                .find(|(name, _)| *name == key)
+                .with_context(|| {
+                    let known: Vec<_> = settings.iter().map(|(name, _)| name.as_str()).collect();
+                    format!("unknown key {key}; known keys: {}", known.join(", "))
+                })?;
+            // Plain value on stdout so scripts can capture it, unless
+            // stdout carries the JSON report.
+            if args.format == OutputFormat::Text {
+                match value {
+                    toml::Value::String(value) => println!("{value}"),
+                    value => println!("{value}"),
+                }
+            }
+            report.setting = Some(Setting {
+                key,
+                value: value.clone(),
+            });
+            report.config = Some(config);
+        }
+        ConfigAction::Set { key, value, scope } => {
+            let path = scope.path(&repo)?;
+            let text = set_in_file(&path, &key, &value)?;
+            if args.dry_run {
+                info!("dry-run: would write {}:\n{text}", path.display());
+                return Ok(());
+            }
+            write_config(&path, &text)?;
+            info!("set {key} in {}", path.display());
+        }
+        ConfigAction::Init { scope, force } => {
+            let path = scope.path(&repo)?;
+            if path.exists() && !force {
+                anyhow::bail!(
+                    "{} already exists; pass --force to overwrite it",
+                    path.display()
+                );
+            }
+            let text = scaffold(&repo)?;
+            if args.dry_run {
+                info!("dry-run: would write {}:\n{text}", path.display());
+                return Ok(());
+            }
+            write_config(&path, &text)?;
+            info!("wrote {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+fn write_config(path: &std::path::Path, text: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, text).with_context(|| format!("write config {}", path.display()))
+}
+
+/// A starter config filled in from the repo: the remotes of a fork
+/// checkout, else `origin` (or the only remote), and the branch the fetch
+/// remote's HEAD points at.
+fn scaffold(repo: &GitRepo) -> Result<String> {
+    let remotes = repo.remote_names()?;
+    let known = if remotes.is_empty() {
+        "none yet".to_string()
+    } else {
+        remotes.join(", ")
+    };
+    // Writing `remote` alone would turn fork detection off, so a fork
+    // gets both of its remotes spelled out.
+    let (remote, remotes_section) = match detect_fork(&repo.remote_urls()?) {
+        Some((fetch, push)) => (
+            fetch.clone(),
+            format!(
+                "# This clone looks like a fork (remotes here: {known}): fetch from the\n\
+                 # repository it was forked from...\n\
+                 remote = \"{fetch}\"\n\
+                 # ...and push to the fork.\n\
+                 push_remote = \"{push}\""
+            ),
+        ),
+        None => {
+            let remote = remotes
+                .iter()
+                .find(|name| *name == "origin")
+                .or(remotes.first())
+                .cloned()
+                .unwrap_or_else(|| "origin".to_string());
+            let section = format!(
+                "# Remote to fetch from and push to (remotes here: {known}).\n\
+                 remote = \"{remote}\"\n\
+                 # Push somewhere else, e.g. to your fork.\n\
+                 # push_remote = \"{remote}\""
+            );
+            (remote, section)
+        }
+    };
+    let main = repo
+        .remote_head_default_branch(&remote)
+        .unwrap_or_else(|_| "main".to_string());
+
+    Ok(format!(
+        r#"# git-helper configuration, generated by `git-helper config init`.
+# Later files override earlier ones: ~/.config/git-helper/config.toml,
+# .git-helper.toml, .git/git-helper.toml, then GIT_HELPER_* variables.
+
+[defaults]
+# Branch that feature branches are rebased onto.
+main = "{main}"
+{remotes_section}
+
+[sync]
+# Stash uncommitted changes around `sync` instead of refusing to run.
+autostash = false
+
+[transport]
+# "cli" runs the git binary; "libgit2" works without it.
+backend = "cli"
+
+[backup]
+# Tips of rewritten branches are kept under refs/git-helper/backup/.
+# Delete backups older than this many days (0 keeps them forever)...
+max_age_days = 30
+# ...and all but this many per branch, newest first (0 keeps them all).
+max_count = 20
+"#
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitx::test_support::TestRepo;
+
+    #[test]
+    fn test_scaffold_is_valid_config() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo
+            .repo
+            .remote("upstream", "https://example.com/x.git")?;
+        let repo = GitRepo {
+            inner: git2::Repository::open(test_repo.path())?,
+        };
+
+        let text = scaffold(&repo)?;
+        assert!(text.contains("remote = \"upstream\""));
+        assert!(text.contains("# push_remote = \"upstream\""));
+
+        let path = test_repo.path().join("scaffold.toml");
+        std::fs::write(&path, &text)?;
+        let updated = set_in_file(&path, "sync.autostash", "true")?;
+        assert!(updated.contains("autostash = true"));
+        assert!(updated.contains("# Stash uncommitted changes"));
+
+        // A fork keeps both remotes, as detection would have picked them.
+        test_repo
+            .repo
+            .remote("origin", "https://example.com/me/x.git")?;
+        test_repo
+            .repo
+            .remote_set_url("upstream", "https://example.com/org/x.git")?;
+        let defaults = scaffold(&repo)?.parse::<toml::Table>()?["defaults"].clone();
+        assert_eq!(defaults["remote"].as_str(), Some("upstream"));
+        assert_eq!(defaults["push_remote"].as_str(), Some("origin"));
+        Ok(())
+    }
+}
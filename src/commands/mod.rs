@@ -1 +1,7 @@
+pub mod apply;
+pub mod backups;
+pub mod clean;
+pub mod config;
+pub mod stack;
 pub mod sync;
+pub mod undo;
@@ -0,0 +1,326 @@
+use crate::commands::sync::{apply_plan, simulate_plan};
+use crate::config::{ResolvedConfig, Strategy};
+use crate::gitx::{GitRepo, OpenRepoOpts};
+use crate::plan::{SyncOp, SyncPlan};
+use crate::report::Report;
+use crate::stack::Stack;
+use crate::util::print_plan;
+use anyhow::Result;
+use tracing::info;
+
+pub struct StackArgs {
+    pub dry_run: bool,
+    pub config_path: Option<String>,
+    pub action: StackAction,
+}
+
+pub enum StackAction {
+    /// Branch off `parent` (default: the current branch) and record it.
+    Create {
+        name: String,
+        parent: Option<String>,
+    },
+    Show,
+    SetParent {
+        branch: String,
+        parent: String,
+    },
+    Remove {
+        branch: String,
+    },
+    Restack,
+}
+
+pub fn run_stack(args: StackArgs, report: &mut Report) -> Result<()> {
+    let repo = GitRepo::discover(OpenRepoOpts {
+        workdir: ".".into(),
+    })?;
+    let path = Stack::path(&repo);
+    let mut stack = Stack::load(&path)?;
+
+    match args.action {
+        StackAction::Create { name, parent } => {
+            let parent = match parent {
+                Some(parent) => parent,
+                None => repo.current_branch_name()?,
+            };
+            let tip = repo.local_tip(&parent)?;
+            stack.set_parent(&name, &parent)?;
+            if args.dry_run {
+                info!("dry-run: would create {name} on top of {parent}");
+                return Ok(());
+            }
+            repo.create_branch(&name, tip)?;
+            stack.save(&path)?;
+            repo.switch_to(&name)?;
+            info!("created {name} stacked on {parent}");
+        }
+        StackAction::Show => {
+            for root in stack.roots() {
+                info!("stack:\n{}", render_tree(&stack, &root));
+            }
+            report.stack = Some(stack);
+        }
+        StackAction::SetParent { branch, parent } => {
+            repo.local_tip(&branch)?;
+            repo.local_tip(&parent)?;
+            stack.set_parent(&branch, &parent)?;
+            if !args.dry_run {
+                stack.save(&path)?;
+            }
+            info!("{branch} now stacks on {parent}; run `git-helper stack restack` to move it");
+        }
+        StackAction::Remove { branch } => {
+            stack.remove(&branch);
+            if !args.dry_run {
+                stack.save(&path)?;
+            }
+            info!("{branch} removed from its stack");
+        }
+        StackAction::Restack => {
+            let config = ResolvedConfig::load(args.config_path.as_deref(), &repo, None)?;
+            report.config = Some(config.clone());
+            let mut plan = SyncPlan::new();
+            plan_restack(&repo, &config, &stack, &mut plan)?;
+            simulate_plan(&repo, &mut plan)?;
+            print_plan(&plan);
+            report.plan = Some(plan.clone());
+            if !args.dry_run {
+                apply_plan(&repo, &config, &plan, report)?;
+            } else {
+                info!("dry-run: no changes applied");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds a `Restack` for every stacked branch whose parent is moved by an
+/// earlier op in `plan`, or that no longer contains its parent's tip.
+/// Parents are always restacked before their children. Above a parent
+/// that gets a merge, the parent is merged in instead, so a stack kept
+/// with the merge strategy is never rewritten. Each branch's policy has
+/// the last word: `merge` merges its parent in, `ff-only` and `skip` leave
+/// it where it is.
+pub fn plan_restack(
+    repo: &GitRepo,
+    config: &ResolvedConfig,
+    stack: &Stack,
+    plan: &mut SyncPlan,
+) -> Result<()> {
+    let mut moved: Vec<String> = plan
+        .ops
+        .iter()
+        .filter_map(|op| match op {
+            SyncOp::FastForward { branch, .. } | SyncOp::ResetToUpstream { branch, .. } => {
+                Some(branch.clone())
+            }
+            SyncOp::RebaseOnto { src_branch, .. } => Some(src_branch.clone()),
+            SyncOp::MergeFrom { branch, .. } => Some(branch.clone()),
+            _ => None,
+        })
+        .collect();
+    let mut merged: Vec<String> = plan
+        .ops
+        .iter()
+        .filter_map(|op| match op {
+            SyncOp::MergeFrom { branch, .. } => Some(branch.clone()),
+            _ => None,
+        })
+        .collect();
+
+    for root in stack.roots() {
+        for branch in stack.descendants(&root) {
+            if already_synced(plan, &branch) {
+                continue;
+            }
+            let Some(parent) = stack.parent(&branch) else {
+                continue;
+            };
+            let (Ok(tip), Ok(parent_tip)) = (repo.local_tip(&branch), repo.local_tip(parent))
+            else {
+                plan.notes.push(format!(
+                    "{branch} or its parent {parent} no longer exists; not restacked"
+                ));
+                continue;
+            };
+            let parent_merged = merged.iter().any(|m| m == parent);
+            if !parent_merged
+                && !moved.iter().any(|m| m == parent)
+                && repo.is_descendant(tip, parent_tip)?
+            {
+                continue;
+            }
+            let restack = match config.policy(&branch).strategy {
+                Some(Strategy::Skip) => {
+                    plan.notes.push(format!(
+                        "{branch}: left alone by its branch policy; not restacked onto {parent}"
+                    ));
+                    continue;
+                }
+                Some(Strategy::FfOnly) => {
+                    plan.notes.push(format!(
+                        "{branch}: its branch policy only fast-forwards it; \
+                         not restacked onto {parent}"
+                    ));
+                    continue;
+                }
+                Some(Strategy::Merge) => false,
+                Some(Strategy::Rebase) | None => !parent_merged,
+            };
+            if restack {
+                plan.push(SyncOp::Restack {
+                    branch: branch.clone(),
+                    parent: parent.to_string(),
+                    base: repo.merge_base(tip, parent_tip)?,
+                });
+                moved.push(branch);
+            } else {
+                plan.push(SyncOp::MergeFrom {
+                    branch: branch.clone(),
+                    from: parent.to_string(),
+                    message: config.merge_commit_message(parent, &branch),
+                });
+                moved.push(branch.clone());
+                merged.push(branch);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether an earlier op already brings `branch` up to date with its base.
+fn already_synced(plan: &SyncPlan, branch: &str) -> bool {
+    plan.ops.iter().any(|op| match op {
+        SyncOp::RebaseOnto { src_branch, .. } => src_branch == branch,
+        SyncOp::MergeFrom { branch: merged, .. } => merged == branch,
+        _ => false,
+    })
+}
+
+fn render_tree(stack: &Stack, branch: &str) -> String {
+    let mut out = format!("{branch}\n");
+    render_children(stack, branch, 1, &mut out);
+    out
+}
+
+fn render_children(stack: &Stack, branch: &str, depth: usize, out: &mut String) {
+    for child in stack.children(branch) {
+        out.push_str(&format!("{}└─ {child}\n", "   ".repeat(depth - 1)));
+        render_children(stack, &child, depth + 1, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BranchPolicy;
+    use crate::gitx::test_support::TestRepo;
+    use git2::Repository;
+
+    #[test]
+    fn test_render_tree() {
+        let mut stack = Stack::default();
+        stack.set_parent("a", "main").unwrap();
+        stack.set_parent("b", "a").unwrap();
+        stack.set_parent("c", "main").unwrap();
+        assert_eq!(render_tree(&stack, "main"), "main\n└─ a\n   └─ b\n└─ c\n");
+    }
+
+    #[test]
+    fn test_branches_above_a_merged_parent_are_merged_too() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("base.txt", "base\n", "base")?;
+        for (branch, parent) in [("a", "main"), ("b", "a"), ("c", "b")] {
+            test_repo.checkout(parent)?;
+            test_repo.branch(branch)?;
+            test_repo.checkout(branch)?;
+            test_repo.commit_file(&format!("{branch}.txt"), "work\n", branch)?;
+        }
+        test_repo.checkout("main")?;
+        test_repo.commit_file("main.txt", "new\n", "main moves on")?;
+        let repo = GitRepo {
+            inner: Repository::open(test_repo.path())?,
+        };
+        let config = ResolvedConfig::from_layers(&[], &repo, None)?;
+        let mut stack = Stack::default();
+        stack.set_parent("a", "main")?;
+        stack.set_parent("b", "a")?;
+        stack.set_parent("c", "b")?;
+
+        let mut plan = SyncPlan::new();
+        plan.push(SyncOp::MergeFrom {
+            branch: "a".to_string(),
+            from: "main".to_string(),
+            message: config.merge_commit_message("main", "a"),
+        });
+        plan_restack(&repo, &config, &stack, &mut plan)?;
+
+        let ops: Vec<String> = plan.ops.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            ops,
+            ["merge main into a", "merge a into b", "merge b into c"]
+        );
+        assert!(matches!(
+            &plan.ops[1],
+            SyncOp::MergeFrom { message, .. } if message == "Merge branch 'a' into b"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_restack_follows_branch_policies() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("base.txt", "base\n", "base")?;
+        test_repo.branch("a")?;
+        test_repo.checkout("a")?;
+        test_repo.commit_file("a.txt", "work\n", "a")?;
+        for branch in ["skipped", "ff", "merged", "rebased"] {
+            test_repo.checkout("a")?;
+            test_repo.branch(branch)?;
+            test_repo.checkout(branch)?;
+            test_repo.commit_file(&format!("{branch}.txt"), "work\n", branch)?;
+        }
+        test_repo.checkout("a")?;
+        test_repo.commit_file("a.txt", "more\n", "a moves on")?;
+        let repo = GitRepo {
+            inner: Repository::open(test_repo.path())?,
+        };
+        let mut config = ResolvedConfig::from_layers(&[], &repo, None)?;
+        for (branch, strategy) in [
+            ("skipped", Strategy::Skip),
+            ("ff", Strategy::FfOnly),
+            ("merged", Strategy::Merge),
+        ] {
+            config.branches.insert(
+                branch.to_string(),
+                BranchPolicy {
+                    strategy: Some(strategy),
+                    ..Default::default()
+                },
+            );
+        }
+        let mut stack = Stack::default();
+        stack.set_parent("a", "main")?;
+        for branch in ["skipped", "ff", "merged", "rebased"] {
+            stack.set_parent(branch, "a")?;
+        }
+
+        let mut plan = SyncPlan::new();
+        plan_restack(&repo, &config, &stack, &mut plan)?;
+
+        let mut ops: Vec<String> = plan.ops.iter().map(ToString::to_string).collect();
+        ops.sort();
+        assert_eq!(ops, ["merge a into merged", "restack rebased onto a"]);
+        let mut notes = plan.notes.clone();
+        notes.sort();
+        assert_eq!(
+            notes,
+            [
+                "ff: its branch policy only fast-forwards it; not restacked onto a",
+                "skipped: left alone by its branch policy; not restacked onto a",
+            ]
+        );
+        Ok(())
+    }
+}
@@ -1,8 +1,22 @@
-use crate::config::ResolvedConfig;
-use crate::gitx::{GitRepo, OpenRepoOpts};
-use crate::plan::{SyncOp, SyncPlan};
+use crate::commands::backups::expire_backups;
+use crate::commands::clean::plan_cleanup;
+use crate::commands::stack::plan_restack;
+use crate::config::{ResolvedConfig, Strategy};
+use crate::gitx::{
+    BranchStatus, GitRepo, GitxError, MergeOutcome, OpenRepoOpts, RebaseOutcome, UnstashOutcome,
+};
+use crate::journal::Recorder;
+use crate::plan::{
+    AlreadyUpstream, DiscardedCommit, Precondition, PredictedConflict, SyncOp, SyncPlan, short,
+};
+use crate::progress::PlanProgress;
+use crate::report::{OpStatus, PartialSuccess, Report};
+use crate::stack::Stack;
 use crate::util::print_plan;
-use anyhow::Result;
+use anyhow::{Context, Result};
+use git2::Oid;
+use indicatif::ProgressBar;
+use std::collections::BTreeMap;
 use tracing::{info, warn};
 
 pub struct SyncArgs {
@@ -11,12 +25,45 @@ pub struct SyncArgs {
     pub push: bool,
     pub non_interactive: bool,
     pub config_path: Option<String>,
+    pub resume: Option<ResumeAction>,
+    pub plan_out: Option<String>,
+    pub all: bool,
+    pub clean: bool,
+    /// Overrides the branch policy's strategy for the current branch.
+    pub strategy: Option<Strategy>,
+    /// Reset a main that diverged from its remote to the remote, instead of
+    /// stopping.
+    pub reset_main: bool,
 }
 
-pub fn run_sync(args: SyncArgs) -> Result<()> {
+/// How to proceed with a rebase or merge that a previous `sync` stopped on
+/// conflicts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumeAction {
+    Continue,
+    Skip,
+    Abort,
+}
+
+pub fn run_sync(args: SyncArgs, report: &mut Report) -> Result<()> {
     let repo = GitRepo::discover(OpenRepoOpts {
         workdir: ".".into(),
     })?;
+
+    if let Some(action) = args.resume {
+        return resume_sync(&repo, action, args.dry_run);
+    }
+    if repo.rebase_in_progress() {
+        anyhow::bail!(
+            "a rebase is in progress; run `git-helper sync --continue`, `--skip` or `--abort` first"
+        );
+    }
+    if repo.merge_in_progress() {
+        anyhow::bail!(
+            "a merge is in progress; run `git-helper sync --continue` or `--abort` first"
+        );
+    }
+
     let config = ResolvedConfig::load(
         args.config_path.as_deref(),
         &repo,
@@ -24,13 +71,45 @@ pub fn run_sync(args: SyncArgs) -> Result<()> {
     )?;
 
     info!("default branch: {}", config.main);
+    report.config = Some(config.clone());
+    sync(&repo, &config, &args, report)
+}
 
-    let plan = build_sync_plan(&repo, &config, &args)?;
+fn sync(
+    repo: &GitRepo,
+    config: &ResolvedConfig,
+    args: &SyncArgs,
+    report: &mut Report,
+) -> Result<()> {
+    // A force-pushed main or a pruned branch only shows in the
+    // remote-tracking refs once fetched, so a real run fetches before it
+    // plans anything else. A dry run can't fetch and plans from what the
+    // last fetch saw.
+    let fetched = !args.dry_run;
+    if fetched {
+        fetch_remotes(repo, config)?;
+    }
+
+    let mut plan = build_sync_plan(repo, config, args, fetched)?;
+    if !fetched {
+        plan.notes.push(
+            "planned from the remote-tracking refs of the last fetch; \
+             a real run fetches first and plans again"
+                .to_string(),
+        );
+    }
 
     print_plan(&plan);
+    report.plan = Some(plan.clone());
+
+    if let Some(path) = &args.plan_out {
+        std::fs::write(path, serde_json::to_string_pretty(&plan)?)
+            .with_context(|| format!("write plan to {path}"))?;
+        info!("plan written to {path}");
+    }
 
     if !args.dry_run {
-        apply_plan(&repo, &config, &plan, &args)?;
+        apply_plan(repo, config, &plan, report)?;
     } else {
         info!("dry-run: no changes applied");
     }
@@ -38,72 +117,1140 @@ pub fn run_sync(args: SyncArgs) -> Result<()> {
     Ok(())
 }
 
-fn build_sync_plan(repo: &GitRepo, config: &ResolvedConfig, args: &SyncArgs) -> Result<SyncPlan> {
+/// Fetches and prunes every remote the sync reads, ahead of planning. Only
+/// remote-tracking refs move, so there is nothing to journal, and the
+/// fetch is not one of the plan's ops.
+fn fetch_remotes(repo: &GitRepo, config: &ResolvedConfig) -> Result<()> {
+    let mut fetch = SyncPlan::new();
+    for remote in config.remotes() {
+        fetch.push(SyncOp::FetchPrune {
+            remote: remote.to_string(),
+        });
+    }
+    let progress = PlanProgress::new(&fetch);
+    for (index, op) in fetch.ops.iter().enumerate() {
+        let bar = progress.start(index);
+        let result = apply_op(repo, config, op, &bar);
+        let status = match &result {
+            Ok(status) => status.clone(),
+            Err(err) => OpStatus::Failed {
+                reason: format!("{err:#}"),
+            },
+        };
+        progress.finish(index, &status);
+        result?;
+    }
+    Ok(())
+}
+
+/// Plans the sync from the refs as they are now. Unless the remotes were
+/// just `fetched`, the plan starts by fetching them.
+fn build_sync_plan(
+    repo: &GitRepo,
+    config: &ResolvedConfig,
+    args: &SyncArgs,
+    fetched: bool,
+) -> Result<SyncPlan> {
     let mut plan = SyncPlan::new();
 
-    plan.push(SyncOp::FetchPrune {
-        remote: config.remote.clone(),
-    });
+    if !fetched {
+        for remote in config.remotes() {
+            plan.push(SyncOp::FetchPrune {
+                remote: remote.to_string(),
+            });
+        }
+    }
 
     let main = config.main.clone();
-    if !repo.is_ff_up_to_remote(&main, &config.remote)? {
-        plan.push(SyncOp::FastForward {
-            branch: main.clone(),
-        });
-    }
+    // Where main will point once the plan's fast-forward has run.
+    let main_tip = match repo.branch_status(&main, &config.remote)? {
+        BranchStatus::Behind | BranchStatus::MissingLocal => {
+            let target = repo.remote_tip(&main, &config.remote)?;
+            plan.push(SyncOp::FastForward {
+                remote: config.remote.clone(),
+                branch: main.clone(),
+                target,
+            });
+            target
+        }
+        BranchStatus::Diverged { ahead, behind } => {
+            let local_tip = repo.local_tip(&main)?;
+            let remote_tip = repo.remote_tip(&main, &config.remote)?;
+            let rewritten = upstream_rewrite(repo, &config.remote, &main, local_tip, remote_tip)?;
+            if !args.reset_main {
+                let (equivalent, discarded) =
+                    reset_losses(repo, &config.remote, &main, local_tip, remote_tip)?;
+                let mut lines: Vec<String> = discarded
+                    .iter()
+                    .map(|d| format!("  {} {}", short(&d.commit), d.summary))
+                    .collect();
+                lines.extend(equivalent.iter().map(|e| {
+                    format!(
+                        "  {} {} (already in {})",
+                        short(&e.commit),
+                        e.summary,
+                        e.into
+                    )
+                }));
+                return Err(GitxError::Diverged {
+                    remote: config.remote.clone(),
+                    branch: main.clone(),
+                    ahead,
+                    behind,
+                })
+                .with_context(|| {
+                    format!(
+                        "cannot fast-forward {main}{}; resetting it to {}/{main} would discard:\n{}\n\
+                         rerun with --reset-main to reset it (its old tip is kept in a backup ref), \
+                         or reconcile it by hand",
+                        rewritten.map(|r| format!(" ({r})")).unwrap_or_default(),
+                        config.remote,
+                        lines.join("\n")
+                    )
+                });
+            }
+            if let Some(rewritten) = rewritten {
+                plan.notes.push(format!("{main}: {rewritten}"));
+            }
+            plan.push(SyncOp::ResetToUpstream {
+                remote: config.remote.clone(),
+                branch: main.clone(),
+                tip: local_tip,
+            });
+            remote_tip
+        }
+        BranchStatus::MissingRemote => {
+            warn!("no {}/{main}; syncing against local {main}", config.remote);
+            repo.local_tip(&main)?
+        }
+        BranchStatus::UpToDate | BranchStatus::Ahead => repo.local_tip(&main)?,
+    };
 
     let current = repo.current_branch_name()?;
-    if current != main && !repo.is_branch_ancestor_of(&current, &main)? {
-        plan.push(SyncOp::RebaseOnto {
-            src_branch: current.clone(),
-            onto_branch: main.clone(),
-            non_interactive: args.non_interactive,
-        });
+    let mut rebase_candidates = vec![];
+    if current != main {
+        let strategy = args.strategy.or(config.policy(&current).strategy);
+        match strategy.unwrap_or(Strategy::Rebase) {
+            Strategy::Rebase | Strategy::Merge => rebase_candidates.push(current.clone()),
+            Strategy::FfOnly => {
+                plan_fast_forward(repo, &config.remote, &current, &mut plan)?;
+            }
+            Strategy::Skip => plan
+                .notes
+                .push(format!("{current}: left alone by its branch policy")),
+        }
     }
 
-    if args.push {
+    if args.all {
+        // In a fork, feature branches track the push remote.
+        for (remote, branch) in config
+            .remotes()
+            .into_iter()
+            .map(|remote| Ok((remote, repo.tracked_branches(remote)?)))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flat_map(|(remote, branches)| branches.into_iter().map(move |b| (remote, b)))
+        {
+            if branch == main {
+                continue;
+            }
+            let policy = config.policy(&branch);
+            if policy.strategy == Some(Strategy::Skip) {
+                if branch != current {
+                    plan.notes
+                        .push(format!("{branch}: left alone by its branch policy"));
+                }
+                continue;
+            }
+            if !plan_fast_forward(repo, remote, &branch, &mut plan)? {
+                continue;
+            }
+            let rebase = match policy.strategy {
+                Some(Strategy::Rebase | Strategy::Merge) => true,
+                Some(Strategy::FfOnly | Strategy::Skip) => false,
+                None => repo.branch_config_bool(&branch, "githelper-rebase")? == Some(true),
+            };
+            if branch != current && rebase {
+                rebase_candidates.push(branch);
+            }
+        }
+    }
+
+    // A stacked branch follows its parent; only the bottom of a stack that
+    // sits on its base is rebased onto it, and the rest restacked after it.
+    let stack = Stack::load(&Stack::path(repo))?;
+    let mut rebase_roots: Vec<(String, String, Strategy)> = vec![];
+    for branch in rebase_candidates {
+        let policy = config.policy(&branch);
+        let strategy = if branch == current {
+            args.strategy.or(policy.strategy)
+        } else {
+            policy.strategy
+        };
+        let base = policy.base.unwrap_or_else(|| main.clone());
+        if let Some(root) = stack_root(&stack, &branch, &base)
+            && !rebase_roots.iter().any(|(planned, _, _)| *planned == root)
+        {
+            rebase_roots.push((root, base, strategy.unwrap_or(Strategy::Rebase)));
+        }
+    }
+
+    for (branch, base, strategy) in rebase_roots {
+        let tip = planned_tip(repo, &plan, &branch)?;
+        let base_tip = if base == main {
+            main_tip
+        } else {
+            planned_tip(repo, &plan, &base)
+                .with_context(|| format!("{branch}: base branch {base} not found"))?
+        };
+        if repo.is_descendant(tip, base_tip)? {
+            continue;
+        }
+        if strategy == Strategy::Merge {
+            plan.push(SyncOp::MergeFrom {
+                message: config.merge_commit_message(&base, &branch),
+                branch,
+                from: base,
+            });
+        } else {
+            // If the remote main was rewritten under the branch, its old
+            // commits are only in the reflog; replay from there so they
+            // aren't carried over as the branch's own.
+            let mut upstream = None;
+            if base == main {
+                let remote_ref = format!("refs/remotes/{}/{main}", config.remote);
+                if let Some(fork_point) = repo.reflog_fork_point(&remote_ref, tip)?
+                    && fork_point != base_tip
+                    && !repo.is_descendant(base_tip, fork_point)?
+                {
+                    plan.notes.push(format!(
+                        "{branch}: {}/{main} was rewritten since {branch} forked at {}; \
+                         rebasing only the commits after it",
+                        config.remote,
+                        short(&fork_point)
+                    ));
+                    upstream = Some(fork_point);
+                }
+            }
+            plan.push(SyncOp::RebaseOnto {
+                src_branch: branch,
+                onto_branch: base,
+                non_interactive: args.non_interactive,
+                upstream,
+            });
+        }
+    }
+
+    plan_restack(repo, config, &stack, &mut plan)?;
+
+    if args.clean {
+        plan_cleanup(repo, config, main_tip, &current, false, &mut plan)?;
+    }
+
+    let touches_worktree = plan.ops.iter().any(|op| match op {
+        SyncOp::FastForward { branch, .. } => *branch == current,
+        SyncOp::ResetToUpstream { branch, .. } => *branch == current,
+        SyncOp::RebaseOnto { src_branch, .. } => *src_branch == current,
+        SyncOp::Restack { branch, .. } => *branch == current,
+        SyncOp::MergeFrom { branch, .. } => *branch == current,
+        _ => false,
+    });
+    let autostash = touches_worktree && repo.is_dirty()?;
+    if autostash {
+        if !config.autostash {
+            return Err(GitxError::DirtyWorktree {
+                path: repo.workdir(),
+            })
+            .context(
+                "working tree has uncommitted changes; commit or stash them, \
+                 or set `[sync] autostash = true`",
+            );
+        }
+        // Right after any fetches, before anything checks out or rebases.
+        let fetches = plan
+            .ops
+            .iter()
+            .take_while(|op| matches!(op, SyncOp::FetchPrune { .. }))
+            .count();
+        plan.ops.insert(fetches, SyncOp::Stash);
+    }
+
+    // Pushes only need the refs, so changes are back in the worktree
+    // before them and a failed push can't strand them in the stash.
+    if autostash {
+        plan.push(SyncOp::Unstash);
+    }
+
+    // `--push` always pushes; otherwise `branch.<name>.githelper-push`,
+    // the branch policy, then `githelper.push` / `[sync] push`, decide.
+    let policy = config.policy(&current);
+    let push = args.push
+        || repo
+            .branch_config_bool(&current, "githelper-push")?
+            .or(policy.push)
+            .unwrap_or(config.push);
+    let rebased = plan.ops.iter().any(|op| match op {
+        SyncOp::RebaseOnto { src_branch, .. } => *src_branch == current,
+        SyncOp::Restack { branch, .. } => *branch == current,
+        _ => false,
+    });
+    // In a fork, keep the fork's main in step with the upstream one.
+    if push
+        && current != main
+        && config.push_remote != config.remote
+        && repo.remote_tip(&main, &config.push_remote).ok() != Some(main_tip)
+    {
         plan.push(SyncOp::PushIfFastForward {
-            remote: config.remote.clone(),
-            branch: current.clone(),
+            remote: config.push_remote.clone(),
+            branch: main.clone(),
         });
-        if current != main {
+    }
+    if push && policy.strategy == Some(Strategy::Skip) {
+        plan.notes
+            .push(format!("{current}: not pushed; its branch policy skips it"));
+    } else if push && rebased && policy.force_with_lease == Some(false) {
+        plan.notes.push(format!(
+            "{current}: not pushed; it is rewritten and its branch policy forbids force-with-lease"
+        ));
+    } else if push {
+        if rebased {
+            plan.push(SyncOp::PushForceWithLease {
+                remote: config.push_remote.clone(),
+                branch: current.clone(),
+                expected: repo.remote_tip(&current, &config.push_remote).ok(),
+            });
+        } else {
             plan.push(SyncOp::PushIfFastForward {
-                remote: config.remote.clone(),
+                remote: config.push_remote.clone(),
                 branch: current.clone(),
-            })
+            });
         }
     }
 
+    simulate_plan(repo, &mut plan)?;
+    record_preconditions(repo, config, &mut plan)?;
     Ok(plan)
 }
 
-fn apply_plan(
-    repo: &GitRepo,
-    _config: &ResolvedConfig,
-    plan: &SyncPlan,
-    _args: &SyncArgs,
-) -> Result<()> {
+/// Replays the plan's rebases, restacks and merges in memory, in order,
+/// and records every commit expected to stop on conflicts and every commit
+/// dropped as already upstream. No ref moves.
+pub fn simulate_plan(repo: &GitRepo, plan: &mut SyncPlan) -> Result<()> {
+    // Where each branch points once the ops so far have run.
+    let mut tips: BTreeMap<String, Oid> = BTreeMap::new();
+    let tip = |tips: &BTreeMap<String, Oid>, branch: &str| match tips.get(branch) {
+        Some(oid) => Ok(*oid),
+        None => repo.local_tip(branch),
+    };
+    let summary = |oid: Oid| -> Result<String> {
+        let commit = repo.inner.find_commit(oid)?;
+        Ok(commit.summary().unwrap_or_default().to_string())
+    };
+
+    let mut conflicts = vec![];
+    let mut already_upstream = vec![];
+    let mut discarded = vec![];
     for op in &plan.ops {
-        match op {
-            SyncOp::FetchPrune { remote } => {
-                repo.fetch_prune(remote)?;
+        let (branch, into, simulated) = match op {
+            SyncOp::FastForward { branch, target, .. } => {
+                tips.insert(branch.clone(), *target);
+                continue;
             }
-            SyncOp::FastForward { branch } => {
-                repo.fast_forward_branch(branch)?;
+            SyncOp::ResetToUpstream {
+                remote,
+                branch,
+                tip: old_tip,
+            } => {
+                let remote_tip = repo.remote_tip(branch, remote)?;
+                let (equivalent, lost) = reset_losses(repo, remote, branch, *old_tip, remote_tip)?;
+                already_upstream.extend(equivalent);
+                discarded.extend(lost);
+                tips.insert(branch.clone(), remote_tip);
+                continue;
             }
             SyncOp::RebaseOnto {
                 src_branch,
                 onto_branch,
-                non_interactive,
+                upstream,
+                ..
+            } => {
+                let (src, onto) = (tip(&tips, src_branch)?, tip(&tips, onto_branch)?);
+                let upstream = match upstream {
+                    Some(upstream) => *upstream,
+                    None => repo.merge_base(src, onto)?,
+                };
+                (
+                    src_branch,
+                    onto_branch,
+                    repo.simulate_rebase(upstream, onto, src)?,
+                )
+            }
+            SyncOp::Restack {
+                branch,
+                parent,
+                base,
             } => {
-                repo.rebase_onto(src_branch, onto_branch, *non_interactive)?;
+                let (src, onto) = (tip(&tips, branch)?, tip(&tips, parent)?);
+                (branch, parent, repo.simulate_rebase(*base, onto, src)?)
             }
-            SyncOp::PushIfFastForward { branch, remote } => {
-                if let Err(_e) = repo.push_if_ff(remote, branch) {
-                    warn!("push skipped for {} ({})", remote, branch);
+            SyncOp::MergeFrom {
+                branch,
+                from,
+                message,
+            } => {
+                let theirs = tip(&tips, from)?;
+                let (new_tip, paths) = repo.simulate_merge(tip(&tips, branch)?, theirs, message)?;
+                tips.insert(branch.clone(), new_tip);
+                if let Some(paths) = paths {
+                    conflicts.push(PredictedConflict {
+                        branch: branch.clone(),
+                        commit: theirs,
+                        summary: format!("merge of {from}"),
+                        paths,
+                    });
                 }
+                continue;
+            }
+            _ => continue,
+        };
+        tips.insert(branch.clone(), simulated.tip);
+        for commit in simulated.dropped {
+            already_upstream.push(AlreadyUpstream {
+                branch: branch.clone(),
+                into: into.clone(),
+                commit,
+                summary: summary(commit)?,
+            });
+        }
+        for conflict in simulated.conflicts {
+            conflicts.push(PredictedConflict {
+                branch: branch.clone(),
+                commit: conflict.commit,
+                summary: summary(conflict.commit)?,
+                paths: conflict.paths,
+            });
+        }
+    }
+    plan.conflicts = conflicts;
+    plan.already_upstream = already_upstream;
+    plan.discarded = discarded;
+    Ok(())
+}
+
+/// The local-only commits of `branch` at `tip` that resetting it to
+/// `<remote>/<branch>` at `remote_tip` would throw away: those whose change
+/// the remote already has, and those it doesn't.
+fn reset_losses(
+    repo: &GitRepo,
+    remote: &str,
+    branch: &str,
+    tip: Oid,
+    remote_tip: Oid,
+) -> Result<(Vec<AlreadyUpstream>, Vec<DiscardedCommit>)> {
+    let equivalents = repo.upstream_equivalents(remote_tip, tip)?;
+    let (mut equivalent, mut discarded) = (vec![], vec![]);
+    for oid in repo.local_only(remote_tip, tip)? {
+        let commit = repo.inner.find_commit(oid)?;
+        let summary = commit.summary().unwrap_or_default().to_string();
+        if equivalents.contains(&oid) {
+            equivalent.push(AlreadyUpstream {
+                branch: branch.to_string(),
+                into: format!("{remote}/{branch}"),
+                commit: oid,
+                summary,
+            });
+        } else {
+            discarded.push(DiscardedCommit {
+                branch: branch.to_string(),
+                commit: oid,
+                summary,
+            });
+        }
+    }
+    Ok((equivalent, discarded))
+}
+
+/// Describes how `<remote>/<branch>` was force-pushed, if its reflog shows
+/// it once pointed at a commit `tip` has but no longer has it.
+fn upstream_rewrite(
+    repo: &GitRepo,
+    remote: &str,
+    branch: &str,
+    tip: Oid,
+    remote_tip: Oid,
+) -> Result<Option<String>> {
+    let remote_ref = format!("refs/remotes/{remote}/{branch}");
+    let Some(synced) = repo.reflog_fork_point(&remote_ref, tip)? else {
+        return Ok(None);
+    };
+    if repo.is_descendant(remote_tip, synced)? {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "{remote}/{branch} was force-pushed and no longer contains {}, which {branch} was synced to",
+        short(&synced)
+    )))
+}
+
+/// Plans fast-forwarding `branch` to `<remote>/<branch>` when it is
+/// behind. Returns `false`, noting why, when the branch can't be synced
+/// with its upstream at all.
+fn plan_fast_forward(
+    repo: &GitRepo,
+    remote: &str,
+    branch: &str,
+    plan: &mut SyncPlan,
+) -> Result<bool> {
+    let planned = plan
+        .ops
+        .iter()
+        .any(|op| matches!(op, SyncOp::FastForward { branch: b, .. } if b == branch));
+    match repo.branch_status(branch, remote)? {
+        BranchStatus::Behind if !planned => plan.push(SyncOp::FastForward {
+            remote: remote.to_string(),
+            branch: branch.to_string(),
+            target: repo.remote_tip(branch, remote)?,
+        }),
+        BranchStatus::Diverged { ahead, behind } => {
+            plan.notes.push(format!(
+                "{branch} has diverged from {remote}/{branch} \
+                 ({ahead} ahead, {behind} behind); left alone"
+            ));
+            return Ok(false);
+        }
+        BranchStatus::MissingRemote => {
+            plan.notes
+                .push(format!("{branch}: upstream {remote}/{branch} is gone"));
+            return Ok(false);
+        }
+        _ => {}
+    }
+    Ok(true)
+}
+
+/// The branch to rebase onto `base` on behalf of `branch`: the bottom of
+/// its stack, or `None` when the stack is based on something else.
+fn stack_root(stack: &Stack, branch: &str, base: &str) -> Option<String> {
+    let ancestors = stack.ancestors(branch);
+    if ancestors.is_empty() {
+        return Some(branch.to_string());
+    }
+    match ancestors.iter().position(|a| a == base)? {
+        0 => Some(branch.to_string()),
+        i => Some(ancestors[i - 1].clone()),
+    }
+}
+
+/// Where `branch` will point once the fast-forwards already in `plan` ran.
+fn planned_tip(repo: &GitRepo, plan: &SyncPlan, branch: &str) -> Result<git2::Oid> {
+    for op in &plan.ops {
+        match op {
+            SyncOp::FastForward {
+                branch: b, target, ..
+            } if b == branch => return Ok(*target),
+            SyncOp::ResetToUpstream {
+                remote, branch: b, ..
+            } if b == branch => return repo.remote_tip(branch, remote),
+            _ => {}
+        }
+    }
+    repo.local_tip(branch)
+}
+
+/// Pins the state the plan was computed from, so a saved plan can refuse to
+/// run once the repository has moved on.
+fn record_preconditions(
+    repo: &GitRepo,
+    config: &ResolvedConfig,
+    plan: &mut SyncPlan,
+) -> Result<()> {
+    plan.head = Some(repo.head_state()?);
+    let mut refs = plan.touched_refs();
+    refs.push(format!("refs/remotes/{}/{}", config.remote, config.main));
+    for reference in refs {
+        let oid = repo.ref_target(&reference)?;
+        plan.preconditions.push(Precondition { reference, oid });
+    }
+    Ok(())
+}
+
+/// Runs the ops of `plan` in order, recording each one's outcome in
+/// `report`. Stops at the first failure, reporting the ops after it as
+/// skipped; a failed push of either kind doesn't stop the plan but ends it
+/// with `PartialSuccess`. Autostashed changes come back on any failure except a
+/// conflict stop, which restores them once the rebase or merge ends.
+pub fn apply_plan(
+    repo: &GitRepo,
+    config: &ResolvedConfig,
+    plan: &SyncPlan,
+    report: &mut Report,
+) -> Result<()> {
+    // Housekeeping only; it must never stop the plan itself.
+    if let Err(err) = expire_backups(repo, config) {
+        warn!("old backups not expired: {err:#}");
+    }
+    let mut recorder = Recorder::start(repo)?;
+    let progress = PlanProgress::new(plan);
+    let mut failed = vec![];
+    for (index, op) in plan.ops.iter().enumerate() {
+        let bar = progress.start(index);
+        let result = recorder.record(&op.to_string(), || apply_op(repo, config, op, &bar));
+        let status = match &result {
+            Ok(status) => status.clone(),
+            Err(err) => OpStatus::Failed {
+                reason: format!("{err:#}"),
+            },
+        };
+        progress.finish(index, &status);
+        report.record(op, status);
+        if let Err(err) = result {
+            if matches!(
+                op,
+                SyncOp::PushIfFastForward { .. } | SyncOp::PushForceWithLease { .. }
+            ) {
+                warn!("{op} failed: {err:#}");
+                failed.push(op.to_string());
+                continue;
             }
+            let stopped = repo.rebase_in_progress() || repo.merge_in_progress();
+            if stopped && let Err(e) = recorder.stop() {
+                warn!("`sync --continue` won't be undoable with this run: {e:#}");
+            }
+            for op in &plan.ops[index + 1..] {
+                let status = match op {
+                    SyncOp::Unstash if !stopped => restore_autostash(repo).unwrap_or_else(|e| {
+                        warn!("stashed changes not restored: {e:#}");
+                        OpStatus::Failed {
+                            reason: format!("{e:#}"),
+                        }
+                    }),
+                    _ => OpStatus::Skipped {
+                        reason: "not run: an earlier op failed".to_string(),
+                    },
+                };
+                report.record(op, status);
+            }
+            return Err(err);
         }
     }
+    if !failed.is_empty() {
+        return Err(PartialSuccess { failed }.into());
+    }
     Ok(())
 }
+
+fn apply_op(
+    repo: &GitRepo,
+    config: &ResolvedConfig,
+    op: &SyncOp,
+    progress: &ProgressBar,
+) -> Result<OpStatus> {
+    match op {
+        SyncOp::FetchPrune { remote } => {
+            repo.fetch_prune(config.transport, remote, progress)?;
+        }
+        SyncOp::FastForward {
+            remote,
+            branch,
+            target,
+        } => {
+            // A saved plan fetches again before this; moving to whatever
+            // that fetch brought would skip the review the plan was for.
+            repo.fast_forward_branch(branch, remote, *target)?;
+        }
+        SyncOp::RebaseOnto {
+            src_branch,
+            onto_branch,
+            non_interactive,
+            upstream,
+        } => {
+            let outcome = repo.rebase_onto(src_branch, onto_branch, *upstream, *non_interactive)?;
+            return finish_rebase(src_branch, outcome);
+        }
+        SyncOp::Restack {
+            branch,
+            parent,
+            base,
+        } => {
+            let onto = repo.local_tip(parent)?;
+            let outcome = repo.rebase_branch(branch, *base, onto)?;
+            return finish_rebase(branch, outcome);
+        }
+        SyncOp::MergeFrom {
+            branch,
+            from,
+            message,
+        } => {
+            let outcome = repo.merge_from(branch, from, message)?;
+            return finish_merge(branch, outcome);
+        }
+        SyncOp::PushIfFastForward { branch, remote } => {
+            repo.push_if_ff(config.transport, remote, branch, progress)
+                .with_context(|| format!("push {branch} to {remote}"))?;
+        }
+        SyncOp::PushForceWithLease {
+            remote,
+            branch,
+            expected,
+        } => {
+            repo.push_with_lease(config.transport, remote, branch, *expected, progress)
+                .with_context(|| format!("push {branch} to {remote} with lease"))?;
+        }
+        SyncOp::ResetToUpstream {
+            remote,
+            branch,
+            tip,
+        } => {
+            let backup = repo.reset_to_upstream(branch, remote, *tip)?;
+            info!("{branch}: old tip {} saved as {backup}", short(tip));
+        }
+        SyncOp::DeleteBranch { branch, tip, .. } => {
+            repo.delete_branch(branch, *tip)?;
+        }
+        SyncOp::Stash => {
+            repo.autostash()?;
+        }
+        SyncOp::Unstash => return restore_autostash(repo),
+    }
+    Ok(OpStatus::Success)
+}
+
+fn resume_sync(repo: &GitRepo, action: ResumeAction, dry_run: bool) -> Result<()> {
+    if repo.merge_in_progress() {
+        return resume_merge(repo, action, dry_run);
+    }
+    if !repo.rebase_in_progress() {
+        anyhow::bail!("no rebase or merge in progress");
+    }
+    if dry_run {
+        info!("dry-run: would {action:?} the in-progress rebase");
+        return Ok(());
+    }
+
+    // The branch only moves once the rebase finishes, so recording into
+    // the stopped run's journal lets `undo` take it back to its pre-sync tip.
+    let mut recorder = Recorder::resume(repo)?;
+    let outcome = match action {
+        ResumeAction::Continue => recorder.record("sync --continue", || repo.continue_rebase())?,
+        ResumeAction::Skip => recorder.record("sync --skip", || repo.skip_rebase())?,
+        ResumeAction::Abort => {
+            recorder.record("sync --abort", || repo.abort_rebase())?;
+            recorder.finish()?;
+            info!("rebase aborted; branch restored");
+            restore_autostash(repo)?;
+            return Ok(());
+        }
+    };
+    stop_on_conflict(outcome)?;
+    recorder.finish()?;
+    info!("rebase complete; run `git-helper sync` again to finish any remaining steps");
+    restore_autostash(repo)?;
+    Ok(())
+}
+
+fn resume_merge(repo: &GitRepo, action: ResumeAction, dry_run: bool) -> Result<()> {
+    if action == ResumeAction::Skip {
+        anyhow::bail!("a merge can't be skipped; resolve it and `--continue`, or `--abort`");
+    }
+    if dry_run {
+        info!("dry-run: would {action:?} the in-progress merge");
+        return Ok(());
+    }
+
+    let mut recorder = Recorder::resume(repo)?;
+    if action == ResumeAction::Abort {
+        recorder.record("sync --abort", || repo.abort_merge())?;
+        info!("merge aborted; branch restored");
+    } else {
+        stop_on_merge_conflict(recorder.record("sync --continue", || repo.continue_merge())?)?;
+        info!("merge complete; run `git-helper sync` again to finish any remaining steps");
+    }
+    recorder.finish()?;
+    restore_autostash(repo)?;
+    Ok(())
+}
+
+fn restore_autostash(repo: &GitRepo) -> Result<OpStatus> {
+    match repo.restore_autostash()? {
+        UnstashOutcome::Restored => info!("restored stashed changes"),
+        UnstashOutcome::Missing => {}
+        UnstashOutcome::Conflicted { index } => {
+            warn!(
+                "stashed changes conflict with the synced tree; they are kept in stash@{{{index}}}"
+            );
+            return Ok(OpStatus::Skipped {
+                reason: format!("stashed changes conflict; kept in stash@{{{index}}}"),
+            });
+        }
+    }
+    Ok(OpStatus::Success)
+}
+
+/// Warns about an abandoned in-memory rebase and stops the plan on a
+/// conflicted one.
+fn finish_rebase(branch: &str, outcome: RebaseOutcome) -> Result<OpStatus> {
+    if let RebaseOutcome::Abandoned { commit, paths } = &outcome {
+        let reason = format!("{commit} conflicts in {}", paths.join(", "));
+        warn!("{branch} not rebased: {reason}");
+        return Ok(OpStatus::Skipped { reason });
+    }
+    stop_on_conflict(outcome)?;
+    Ok(OpStatus::Success)
+}
+
+/// Like `finish_rebase`, for the `merge` strategy.
+fn finish_merge(branch: &str, outcome: MergeOutcome) -> Result<OpStatus> {
+    match &outcome {
+        MergeOutcome::Abandoned { paths } => {
+            let reason = format!("merge conflicts in {}", paths.join(", "));
+            warn!("{branch} not merged: {reason}");
+            Ok(OpStatus::Skipped { reason })
+        }
+        MergeOutcome::UpToDate => Ok(OpStatus::Skipped {
+            reason: "already up to date".to_string(),
+        }),
+        _ => {
+            stop_on_merge_conflict(outcome)?;
+            Ok(OpStatus::Success)
+        }
+    }
+}
+
+fn stop_on_merge_conflict(outcome: MergeOutcome) -> Result<()> {
+    let MergeOutcome::Conflicted { commit, paths } = outcome else {
+        return Ok(());
+    };
+    for path in &paths {
+        warn!("conflict: {path}");
+    }
+    Err(GitxError::Conflict {
+        commit: commit.to_string(),
+        paths,
+    })
+    .context(
+        "merge stopped: resolve the conflicts, stage them, then run \
+         `git-helper sync --continue` (or `--abort`); \
+         any autostashed changes are restored once the merge ends",
+    )
+}
+
+fn stop_on_conflict(outcome: RebaseOutcome) -> Result<()> {
+    match outcome {
+        RebaseOutcome::Completed | RebaseOutcome::Abandoned { .. } => Ok(()),
+        RebaseOutcome::Conflicted { commit, paths } => {
+            for path in &paths {
+                warn!("conflict: {path}");
+            }
+            Err(GitxError::Conflict {
+                commit: commit.to_string(),
+                paths,
+            })
+            .context(
+                "rebase stopped: resolve the conflicts, stage them, then run \
+                 `git-helper sync --continue` (or `--skip` / `--abort`); \
+                 any autostashed changes are restored once the rebase ends",
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitx::TransportBackend;
+    use crate::gitx::test_support::TestRepo;
+    use crate::journal::{Journal, journal_dir};
+    use crate::plan::DeleteReason;
+    use git2::{BranchType, Repository, Signature};
+    use tempfile::TempDir;
+
+    fn args() -> SyncArgs {
+        SyncArgs {
+            dry_run: false,
+            main_override: None,
+            push: false,
+            non_interactive: true,
+            config_path: None,
+            resume: None,
+            plan_out: None,
+            all: false,
+            clean: false,
+            strategy: None,
+            reset_main: false,
+        }
+    }
+
+    /// A clone of a bare `origin` whose `main` (base, then "published")
+    /// was fetched, then force-pushed with "published" rewritten.
+    fn force_pushed() -> Result<(TestRepo, GitRepo, ResolvedConfig, TempDir)> {
+        let local = TestRepo::new()?;
+        local.commit_file("base.txt", "base\n", "base")?;
+        local.commit_file("published.txt", "one\n", "published")?;
+        let bare_dir = tempfile::tempdir()?;
+        let bare = Repository::init_bare(bare_dir.path())?;
+        local
+            .repo
+            .remote("origin", bare_dir.path().to_str().unwrap())?;
+        let repo = GitRepo {
+            inner: Repository::open(local.path())?,
+        };
+        let mut config = ResolvedConfig::from_layers(&[], &repo, None)?;
+        config.transport = TransportBackend::Libgit2;
+        let hidden = ProgressBar::hidden();
+        repo.push_if_ff(config.transport, "origin", "main", &hidden)?;
+        repo.fetch_prune(config.transport, "origin", &hidden)?;
+
+        let published = bare.find_commit(bare.refname_to_id("refs/heads/main")?)?;
+        let sig = Signature::now("Test User", "test@example.com")?;
+        let rewritten = bare.commit(
+            None,
+            &sig,
+            &sig,
+            "published, reworded",
+            &published.tree()?,
+            &[&published.parent(0)?],
+        )?;
+        bare.reference("refs/heads/main", rewritten, true, "force-push")?;
+        Ok((local, repo, config, bare_dir))
+    }
+
+    #[test]
+    fn test_first_sync_after_force_push_sees_diverged_main() -> Result<()> {
+        let (local, repo, config, _bare) = force_pushed()?;
+        let mut report = Report::new("sync", false);
+        let err = sync(&repo, &config, &args(), &mut report).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GitxError>(),
+            Some(GitxError::Diverged { .. })
+        ));
+        let published = local.tip("main")?;
+
+        let (local, repo, config, _bare) = force_pushed()?;
+        let reset = SyncArgs {
+            reset_main: true,
+            ..args()
+        };
+        let mut report = Report::new("sync", false);
+        sync(&repo, &config, &reset, &mut report)?;
+        let plan = report.plan.expect("plan recorded");
+        assert!(
+            plan.ops
+                .iter()
+                .any(|op| matches!(op, SyncOp::ResetToUpstream { .. }))
+        );
+        let main = local.repo.find_commit(local.tip("main")?)?;
+        assert_eq!(main.summary(), Some("published, reworded"));
+        assert_ne!(main.id(), published);
+        Ok(())
+    }
+
+    #[test]
+    fn test_first_sync_after_force_push_rebases_from_fork_point() -> Result<()> {
+        let (local, repo, config, _bare) = force_pushed()?;
+        let published = local.tip("main")?;
+        local.branch("feature")?;
+        local.checkout("feature")?;
+        local.commit_file("feature.txt", "mine\n", "feature work")?;
+        local
+            .repo
+            .find_branch("main", BranchType::Local)?
+            .delete()?;
+
+        let mut report = Report::new("sync", false);
+        sync(&repo, &config, &args(), &mut report)?;
+
+        let plan = report.plan.expect("plan recorded");
+        assert_eq!(
+            plan.notes,
+            [format!(
+                "feature: origin/main was rewritten since feature forked at {}; \
+                 rebasing only the commits after it",
+                short(&published)
+            )]
+        );
+        // The fetch ran before planning, outside the plan and its journal.
+        let ops: Vec<String> = plan.ops.iter().map(ToString::to_string).collect();
+        let results: Vec<String> = report.results.iter().map(|r| r.op.to_string()).collect();
+        assert_eq!(results, ops);
+        assert!(!ops.iter().any(|op| op.starts_with("fetch")));
+        assert_eq!(Journal::list(&journal_dir(&repo))?.len(), 1);
+        let tip = local.repo.find_commit(local.tip("feature")?)?;
+        assert_eq!(tip.summary(), Some("feature work"));
+        assert_eq!(tip.parent_id(0)?, local.tip("main")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_op_restores_autostash() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let base = test_repo.commit_file("file.txt", "base\n", "base")?;
+        test_repo.branch("topic")?;
+        test_repo.commit_file("file.txt", "main\n", "main")?;
+        std::fs::write(test_repo.path().join("file.txt"), "dirty\n")?;
+        let repo = GitRepo {
+            inner: Repository::open(test_repo.path())?,
+        };
+        let config = ResolvedConfig::from_layers(&[], &repo, None)?;
+
+        let mut plan = SyncPlan::new();
+        plan.push(SyncOp::Stash);
+        // Planned against a tip `topic` no longer has, so it fails.
+        plan.push(SyncOp::DeleteBranch {
+            branch: "topic".to_string(),
+            reason: DeleteReason::Merged {
+                into: "main".to_string(),
+            },
+            tip: test_repo.tip("main")?,
+        });
+        plan.push(SyncOp::PushIfFastForward {
+            remote: "origin".to_string(),
+            branch: "main".to_string(),
+        });
+        plan.push(SyncOp::Unstash);
+        let mut report = Report::new("sync", false);
+        assert!(apply_plan(&repo, &config, &plan, &mut report).is_err());
+
+        let content = std::fs::read_to_string(test_repo.path().join("file.txt"))?;
+        assert_eq!(content, "dirty\n");
+        assert_eq!(test_repo.tip("topic")?, base);
+        let statuses: Vec<_> = report.results.iter().map(|r| &r.status).collect();
+        assert!(matches!(statuses[1], OpStatus::Failed { .. }));
+        assert!(matches!(statuses[2], OpStatus::Skipped { .. }));
+        assert_eq!(*statuses[3], OpStatus::Success);
+        Ok(())
+    }
+
+    #[test]
+    fn test_continued_rebase_is_recorded_in_the_stopped_run() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("file.txt", "base\n", "base")?;
+        test_repo.branch("feature")?;
+        test_repo.commit_file("file.txt", "main\n", "main change")?;
+        test_repo.checkout("feature")?;
+        let before = test_repo.commit_file("file.txt", "feature\n", "feature change")?;
+        let repo = GitRepo {
+            inner: Repository::open(test_repo.path())?,
+        };
+        let config = ResolvedConfig::from_layers(&[], &repo, None)?;
+        let mut plan = SyncPlan::new();
+        plan.push(SyncOp::RebaseOnto {
+            src_branch: "feature".to_string(),
+            onto_branch: "main".to_string(),
+            non_interactive: true,
+            upstream: None,
+        });
+        let mut report = Report::new("sync", false);
+        assert!(apply_plan(&repo, &config, &plan, &mut report).is_err());
+        assert!(repo.rebase_in_progress());
+
+        std::fs::write(test_repo.path().join("file.txt"), "resolved\n")?;
+        let mut index = repo.inner.index()?;
+        index.add_path(std::path::Path::new("file.txt"))?;
+        index.write()?;
+        resume_sync(&repo, ResumeAction::Continue, false)?;
+
+        assert_ne!(test_repo.tip("feature")?, before);
+        let runs = Journal::list(&journal_dir(&repo))?;
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].head_before, "refs/heads/feature");
+        assert_eq!(runs[0].entries[0].op, "sync --continue");
+        assert_eq!(
+            runs[0].original_refs().get("refs/heads/feature"),
+            Some(&Some(before.to_string()))
+        );
+        assert!(!repo.inner.path().join("git-helper-stopped-run").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_restacked_branch_honors_force_with_lease_policy() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("base.txt", "base\n", "base")?;
+        for (branch, parent) in [("parent", "main"), ("child", "parent")] {
+            test_repo.checkout(parent)?;
+            test_repo.branch(branch)?;
+            test_repo.checkout(branch)?;
+            test_repo.commit_file(&format!("{branch}.txt"), "work\n", branch)?;
+        }
+        test_repo.checkout("main")?;
+        test_repo.commit_file("main.txt", "new\n", "main moves on")?;
+        test_repo.checkout("child")?;
+        let repo = GitRepo {
+            inner: Repository::open(test_repo.path())?,
+        };
+        let mut stack = Stack::default();
+        stack.set_parent("parent", "main")?;
+        stack.set_parent("child", "parent")?;
+        stack.save(&Stack::path(&repo))?;
+        let mut config = ResolvedConfig::from_layers(&[], &repo, None)?;
+        config.branches.insert(
+            "child".to_string(),
+            crate::config::BranchPolicy {
+                force_with_lease: Some(false),
+                ..Default::default()
+            },
+        );
+        let push = SyncArgs {
+            push: true,
+            ..args()
+        };
+
+        let plan = build_sync_plan(&repo, &config, &push, true)?;
+
+        assert!(
+            plan.ops
+                .iter()
+                .any(|op| matches!(op, SyncOp::Restack { branch, .. } if branch == "child"))
+        );
+        assert!(
+            !plan
+                .ops
+                .iter()
+                .any(|op| matches!(op, SyncOp::PushForceWithLease { .. }))
+        );
+        assert!(plan.notes.contains(
+            &"child: not pushed; it is rewritten and its branch policy forbids force-with-lease"
+                .to_string()
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_pushes_end_in_partial_success() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("file.txt", "base\n", "base")?;
+        test_repo.branch("done")?;
+        let repo = GitRepo {
+            inner: Repository::open(test_repo.path())?,
+        };
+        let config = ResolvedConfig::from_layers(&[], &repo, None)?;
+
+        let mut plan = SyncPlan::new();
+        plan.push(SyncOp::PushForceWithLease {
+            remote: "origin".to_string(),
+            branch: "main".to_string(),
+            expected: None,
+        });
+        plan.push(SyncOp::PushIfFastForward {
+            remote: "origin".to_string(),
+            branch: "main".to_string(),
+        });
+        plan.push(SyncOp::DeleteBranch {
+            branch: "done".to_string(),
+            reason: DeleteReason::Merged {
+                into: "main".to_string(),
+            },
+            tip: test_repo.tip("done")?,
+        });
+        let mut report = Report::new("sync", false);
+        let err = apply_plan(&repo, &config, &plan, &mut report).unwrap_err();
+
+        let partial = err
+            .downcast_ref::<PartialSuccess>()
+            .expect("partial success");
+        assert_eq!(partial.failed.len(), 2);
+        assert_eq!(report.results[2].status, OpStatus::Success);
+        assert!(repo.inner.find_branch("done", BranchType::Local).is_err());
+        Ok(())
+    }
+}
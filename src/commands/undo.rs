@@ -0,0 +1,166 @@
+use crate::gitx::{GitRepo, GitxError, OpenRepoOpts};
+use crate::journal::{Journal, journal_dir};
+use crate::report::Report;
+use anyhow::{Context, Result};
+use git2::Oid;
+use std::path::Path;
+use tracing::{info, warn};
+
+pub struct UndoArgs {
+    pub dry_run: bool,
+    pub run: Option<String>,
+    pub list: bool,
+    /// Undo even if refs moved after the run, discarding those moves.
+    pub force: bool,
+}
+
+pub fn run_undo(args: UndoArgs, report: &mut Report) -> Result<()> {
+    let repo = GitRepo::discover(OpenRepoOpts {
+        workdir: ".".into(),
+    })?;
+    let dir = journal_dir(&repo);
+
+    if args.list {
+        let journals = Journal::list(&dir)?;
+        for journal in &journals {
+            let status = if journal.undone { " (undone)" } else { "" };
+            let ops: Vec<_> = journal.entries.iter().map(|e| e.op.as_str()).collect();
+            info!("{}{}: {}", journal.id, status, ops.join("; "));
+        }
+        report.runs = Some(journals);
+        return Ok(());
+    }
+
+    let journal = match args.run {
+        Some(id) => Journal::load(&dir, &id)?,
+        None => Journal::list(&dir)?
+            .into_iter()
+            .rev()
+            .find(|journal| !journal.undone)
+            .ok_or_else(|| anyhow::anyhow!("no sync run to undo"))?,
+    };
+    let journal = undo(&repo, &dir, journal, args.dry_run, args.force)?;
+    report.runs = Some(vec![journal]);
+    Ok(())
+}
+
+/// Undoes `journal`'s run (unless `dry_run`) and returns it as saved.
+fn undo(
+    repo: &GitRepo,
+    dir: &Path,
+    mut journal: Journal,
+    dry_run: bool,
+    force: bool,
+) -> Result<Journal> {
+    if journal.undone {
+        anyhow::bail!("run {} was already undone", journal.id);
+    }
+    if repo.rebase_in_progress() || repo.merge_in_progress() {
+        anyhow::bail!("a sync is stopped on conflicts; run `git-helper sync --abort` first");
+    }
+    if repo.is_dirty()? {
+        return Err(GitxError::DirtyWorktree {
+            path: repo.workdir(),
+        })
+        .context("working tree has uncommitted changes; commit or stash them before undoing");
+    }
+
+    let drifted = drifted_refs(repo, &journal)?;
+    if !drifted.is_empty() {
+        if !force {
+            anyhow::bail!(
+                "refs moved since run {} finished; undoing would discard that:\n{}\n\
+                 rerun with `--force` to undo anyway",
+                journal.id,
+                drifted.join("\n")
+            );
+        }
+        warn!(
+            "undoing anyway; refs moved since the run:\n{}",
+            drifted.join("\n")
+        );
+    }
+
+    let original = journal.original_refs();
+    for (name, oid) in &original {
+        match oid {
+            Some(oid) => info!("• reset {name} to {oid}"),
+            None => info!("• delete {name}"),
+        }
+    }
+    info!("• restore HEAD to {}", journal.head_before);
+
+    if dry_run {
+        info!("dry-run: no changes applied");
+        return Ok(journal);
+    }
+
+    for (name, oid) in &original {
+        let oid = oid.as_deref().map(Oid::from_str).transpose()?;
+        // Undoing rewrites the branch too; keep what it is being moved off.
+        if let Some(branch) = name.strip_prefix("refs/heads/")
+            && let Some(tip) = repo.ref_target(name)?
+            && Some(tip) != oid
+        {
+            repo.backup_branch(branch, tip)?;
+        }
+        repo.restore_ref(name, oid)?;
+    }
+    repo.restore_head(&journal.head_before)?;
+
+    journal.undone = true;
+    journal.save(dir)?;
+    info!("undid sync run {}", journal.id);
+    Ok(journal)
+}
+
+/// Refs that no longer point where the run left them, one line each.
+fn drifted_refs(repo: &GitRepo, journal: &Journal) -> Result<Vec<String>> {
+    let mut drifted = vec![];
+    for (name, after) in journal.final_refs() {
+        let current = repo.ref_target(&name)?.map(|oid| oid.to_string());
+        if current != after {
+            let show = |oid: &Option<String>| oid.clone().unwrap_or_else(|| "(deleted)".into());
+            drifted.push(format!(
+                "  {name}: left at {}, now {}",
+                show(&after),
+                show(&current)
+            ));
+        }
+    }
+    Ok(drifted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitx::test_support::TestRepo;
+    use crate::journal::Recorder;
+
+    #[test]
+    fn test_undo_refuses_refs_moved_since_the_run() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let first = test_repo.commit_file("file.txt", "one\n", "one")?;
+        let repo = GitRepo {
+            inner: git2::Repository::open(test_repo.path())?,
+        };
+        let dir = journal_dir(&repo);
+        let mut recorder = Recorder::start(&repo)?;
+        recorder.record("commit", || {
+            test_repo.commit_file("file.txt", "two\n", "two")
+        })?;
+        let later = test_repo.commit_file("file.txt", "three\n", "three")?;
+
+        let journal = Journal::list(&dir)?.remove(0);
+        assert_eq!(drifted_refs(&repo, &journal)?.len(), 1);
+        let err = undo(&repo, &dir, journal, false, false).unwrap_err();
+        assert!(format!("{err:#}").contains("refs/heads/main"));
+        assert_eq!(test_repo.tip("main")?, later);
+
+        let journal = Journal::list(&dir)?.remove(0);
+        undo(&repo, &dir, journal, false, true)?;
+        assert_eq!(test_repo.tip("main")?, first);
+        assert!(Journal::list(&dir)?[0].undone);
+        Ok(())
+    }
+}
@@ -1,61 +1,800 @@
-use crate::gitx::GitRepo;
-use anyhow::Result;
-use serde::Deserialize;
+use crate::gitx::{Expiry, GitRepo, TransportBackend};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use toml::Table;
 
 #[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 struct FileConfig {
     defaults: Option<Defaults>,
+    sync: Option<SyncSection>,
+    transport: Option<TransportSection>,
+    backup: Option<BackupSection>,
+    /// `[branch."<glob>"]` sections, by pattern.
+    branch: Option<BTreeMap<String, BranchPolicy>>,
 }
 
 #[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 struct Defaults {
     main: Option<String>,
     remote: Option<String>,
+    push_remote: Option<String>,
 }
 
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct SyncSection {
+    autostash: Option<bool>,
+    push: Option<bool>,
+    merge_message: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct TransportSection {
+    backend: Option<TransportBackend>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct BackupSection {
+    max_age_days: Option<u32>,
+    max_count: Option<u32>,
+}
+
+/// How `sync` brings a branch up to date with its base.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Replay the branch's commits on top of its base.
+    Rebase,
+    /// Merge the base into the branch.
+    Merge,
+    /// Only fast-forward the branch to its upstream; never rewrite it.
+    FfOnly,
+    /// Leave the branch alone.
+    Skip,
+}
+
+impl std::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Strategy::Rebase => "rebase",
+            Strategy::Merge => "merge",
+            Strategy::FfOnly => "ff-only",
+            Strategy::Skip => "skip",
+        })
+    }
+}
+
+/// Settings from a `[branch."<glob>"]` section. Unset fields fall back to
+/// the global behavior.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BranchPolicy {
+    pub strategy: Option<Strategy>,
+    /// Push the branch after syncing it.
+    pub push: Option<bool>,
+    /// Branch to sync onto instead of `defaults.main`.
+    pub base: Option<String>,
+    /// Whether a rewritten branch may be pushed with `--force-with-lease`.
+    pub force_with_lease: Option<bool>,
+}
+
+/// Whether `name` matches `pattern`: `*` matches within one path
+/// component, `**` across components, `?` any one character but `/`.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    fn go(pattern: &[u8], name: &[u8]) -> bool {
+        match pattern {
+            [] => name.is_empty(),
+            [b'*', b'*', rest @ ..] => (0..=name.len()).any(|i| go(rest, &name[i..])),
+            [b'*', rest @ ..] => (0..=name.len())
+                .take_while(|&i| i == 0 || name[i - 1] != b'/')
+                .any(|i| go(rest, &name[i..])),
+            [b'?', rest @ ..] => name.first().is_some_and(|&c| c != b'/') && go(rest, &name[1..]),
+            [c, rest @ ..] => name.first() == Some(c) && go(rest, &name[1..]),
+        }
+    }
+    go(pattern.as_bytes(), name.as_bytes())
+}
+
+/// Splits a remote URL into `(host, owner, repository name)`, e.g.
+/// `git@github.com:me/tool.git` into `("github.com", "me", "tool")`.
+/// Local paths have an empty host.
+fn repo_slug(url: &str) -> Option<(String, String, String)> {
+    let (host, path) = match url.split_once("://") {
+        Some((_, rest)) => {
+            let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+            let host = authority.rsplit('@').next().unwrap_or(authority);
+            (host.split(':').next().unwrap_or(host), path)
+        }
+        // scp-like `user@host:path`, unless it's a plain path.
+        None => match url.split_once(':') {
+            Some((host, path)) if !host.contains('/') => {
+                (host.rsplit('@').next().unwrap_or(host), path)
+            }
+            _ => ("", url),
+        },
+    };
+    let path = path.trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    let (owner, name) = path.rsplit_once('/')?;
+    if name.is_empty() {
+        return None;
+    }
+    Some((host.to_lowercase(), owner.to_string(), name.to_string()))
+}
+
+/// Recognizes a fork checkout: `origin` and one other remote point at
+/// same-named repositories with different owners on the same host. Returns
+/// `(fetch remote, push remote)`, preferring a remote named `upstream` to
+/// fetch from.
+pub fn detect_fork(remotes: &[(String, String)]) -> Option<(String, String)> {
+    let slugs: Vec<(&str, (String, String, String))> = remotes
+        .iter()
+        .filter_map(|(name, url)| Some((name.as_str(), repo_slug(url)?)))
+        .collect();
+    let (_, (host, owner, repo)) = slugs.iter().find(|(name, _)| *name == "origin")?;
+    let forks: Vec<&str> = slugs
+        .iter()
+        .filter(|(_, (h, o, r))| h == host && r == repo && o != owner)
+        .map(|(name, _)| *name)
+        .collect();
+    let upstream = match forks.as_slice() {
+        [only] => *only,
+        _ => *forks.iter().find(|name| **name == "upstream")?,
+    };
+    Some((upstream.to_string(), "origin".to_string()))
+}
+
+/// Environment variables that set a config key, as `(variable, section, key)`.
+const ENV_KEYS: [(&str, &str, &str); 9] = [
+    ("GIT_HELPER_MAIN", "defaults", "main"),
+    ("GIT_HELPER_REMOTE", "defaults", "remote"),
+    ("GIT_HELPER_PUSH_REMOTE", "defaults", "push_remote"),
+    ("GIT_HELPER_PUSH", "sync", "push"),
+    ("GIT_HELPER_AUTOSTASH", "sync", "autostash"),
+    ("GIT_HELPER_MERGE_MESSAGE", "sync", "merge_message"),
+    ("GIT_HELPER_TRANSPORT", "transport", "backend"),
+    ("GIT_HELPER_BACKUP_MAX_AGE_DAYS", "backup", "max_age_days"),
+    ("GIT_HELPER_BACKUP_MAX_COUNT", "backup", "max_count"),
+];
+
+/// Git config keys that set a config key, as `(git key, section, key, is
+/// boolean)`. Later entries win, so git's own remote settings give way to
+/// ours.
+const GIT_KEYS: [(&str, &str, &str, bool); 8] = [
+    ("checkout.defaultRemote", "defaults", "remote", false),
+    ("remote.pushDefault", "defaults", "push_remote", false),
+    ("githelper.main", "defaults", "main", false),
+    ("githelper.remote", "defaults", "remote", false),
+    ("githelper.push", "sync", "push", true),
+    ("githelper.autostash", "sync", "autostash", true),
+    ("githelper.mergeMessage", "sync", "merge_message", false),
+    ("githelper.transport", "transport", "backend", false),
+];
+
+/// Git config files read for settings, lowest precedence first.
+const GIT_LEVELS: [(git2::ConfigLevel, &str); 4] = [
+    (git2::ConfigLevel::System, "git config --system"),
+    (git2::ConfigLevel::XDG, "git config (~/.config/git/config)"),
+    (git2::ConfigLevel::Global, "git config --global"),
+    (git2::ConfigLevel::Local, "git config --local"),
+];
+
+/// One source of settings. Layers are merged key by key, later ones
+/// winning.
+#[derive(Debug)]
+pub struct Layer {
+    /// A file path, or the environment.
+    pub source: String,
+    pub table: Table,
+}
+
+impl Layer {
+    /// Parses a config file, pointing at the offending line when the TOML
+    /// is invalid or uses a key we don't know.
+    fn parse(source: &str, text: &str) -> Result<Self> {
+        let locate = |err: toml::de::Error| {
+            let line = err
+                .span()
+                .map_or(1, |span| text[..span.start].matches('\n').count() + 1);
+            anyhow::anyhow!("{source}:{line}: {}", err.message())
+        };
+        toml::from_str::<FileConfig>(text).map_err(locate)?;
+        Ok(Self {
+            source: source.to_string(),
+            table: toml::from_str(text).map_err(locate)?,
+        })
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("read config {}", path.display()))?;
+        Self::parse(&path.display().to_string(), &text)
+    }
+
+    /// The `GIT_HELPER_*` variables among `vars` that set config keys.
+    fn from_env(vars: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        let mut table = Table::new();
+        for (name, value) in vars {
+            let Some((_, section, key)) = ENV_KEYS.iter().find(|(var, _, _)| *var == name) else {
+                continue;
+            };
+            let entry = setting(&[section, key], &value)
+                .with_context(|| format!("invalid value in environment variable {name}"))?;
+            merge(&mut table, entry);
+        }
+        Ok(Self {
+            source: "environment".to_string(),
+            table,
+        })
+    }
+
+    /// The `githelper.*` keys, plus the git settings we honor, from one git
+    /// config file. Booleans are read the way git reads them (`yes`, `on`,
+    /// `1`, ...).
+    fn from_git_config(source: &str, config: &git2::Config) -> Result<Self> {
+        let mut table = Table::new();
+        for (name, section, key, boolean) in GIT_KEYS {
+            let value = if boolean {
+                config.get_bool(name).map(|value| value.to_string())
+            } else {
+                config.get_string(name)
+            };
+            let value = match value {
+                Ok(value) => value,
+                Err(e) if e.code() == git2::ErrorCode::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("{source}: invalid {name}")),
+            };
+            let entry = setting(&[section, key], &value)
+                .with_context(|| format!("{source}: invalid value for {name}"))?;
+            merge(&mut table, entry);
+        }
+        Ok(Self {
+            source: source.to_string(),
+            table,
+        })
+    }
+}
+
+/// A table holding just the key at `path` (`["sync", "autostash"]`) set to
+/// `value`, checked against the config schema. `true`/`false` become
+/// booleans, and digits a number where the key takes one; anything else is
+/// a string.
+fn setting(path: &[&str], value: &str) -> Result<Table> {
+    let table = |value| {
+        let (name, tables) = path.split_last().expect("a key path is never empty");
+        let leaf = Table::from_iter([(name.to_string(), value)]);
+        tables.iter().rev().fold(leaf, |inner, table| {
+            Table::from_iter([(table.to_string(), toml::Value::Table(inner))])
+        })
+    };
+    let typed = match value {
+        "true" => toml::Value::Boolean(true),
+        "false" => toml::Value::Boolean(false),
+        _ => toml::Value::String(value.to_string()),
+    };
+    let entry = table(typed);
+    match entry.clone().try_into::<FileConfig>() {
+        Ok(_) => Ok(entry),
+        Err(err) => {
+            let Ok(number) = value.parse::<i64>() else {
+                return Err(err.into());
+            };
+            let entry = table(toml::Value::Integer(number));
+            entry.clone().try_into::<FileConfig>()?;
+            Ok(entry)
+        }
+    }
+}
+
+/// Deep-merges `over` into `base`, tables key by key.
+fn merge(base: &mut Table, over: Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge(base, over),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// A config file `config set` and `config init` can write to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// `$XDG_CONFIG_HOME/git-helper/config.toml`, for every repository.
+    Global,
+    /// `.git-helper.toml` at the worktree root, usually committed.
+    Repo,
+    /// `.git/git-helper.toml`, private to this clone.
+    Local,
+}
+
+impl Scope {
+    pub fn path(self, repo: &GitRepo) -> Result<PathBuf> {
+        match self {
+            Scope::Global => {
+                global_config_path().context("neither XDG_CONFIG_HOME nor HOME is set")
+            }
+            Scope::Repo => Ok(repo
+                .inner
+                .workdir()
+                .context("bare repository has no worktree")?
+                .join(".git-helper.toml")),
+            Scope::Local => Ok(repo.inner.commondir().join("git-helper.toml")),
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/git-helper/config.toml`, falling back to `~/.config`.
+fn global_config_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("git-helper").join("config.toml"))
+}
+
+/// What the machine rather than the repository contributes to the layers.
+struct Host {
+    /// Git config levels to read, lowest first.
+    git_levels: &'static [(git2::ConfigLevel, &'static str)],
+    /// The global file, if there is a place for one.
+    global: Option<PathBuf>,
+    env: Vec<(String, String)>,
+}
+
+impl Host {
+    fn current() -> Self {
+        Self {
+            git_levels: &GIT_LEVELS,
+            global: global_config_path(),
+            env: std::env::vars().collect(),
+        }
+    }
+}
+
+/// Every config layer in precedence order, lowest first: git config, the
+/// global file, `.git-helper.toml` at the worktree root,
+/// `.git/git-helper.toml`, the environment, then the file given with
+/// `--config`. Files that don't exist are skipped, except the one asked
+/// for explicitly.
+pub fn discover_layers(path: Option<&str>, repo: &GitRepo) -> Result<Vec<Layer>> {
+    collect_layers(path, repo, Host::current())
+}
+
+fn collect_layers(path: Option<&str>, repo: &GitRepo, host: Host) -> Result<Vec<Layer>> {
+    let candidates: Vec<PathBuf> = host
+        .global
+        .into_iter()
+        .chain(
+            [Scope::Repo, Scope::Local]
+                .into_iter()
+                .filter_map(|scope| scope.path(repo).ok()),
+        )
+        .collect();
+
+    let mut layers = vec![];
+    let git_config = repo.inner.config()?;
+    for &(level, source) in host.git_levels {
+        match git_config.open_level(level) {
+            Ok(config) => layers.push(Layer::from_git_config(source, &config)?),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    for candidate in candidates.iter().filter(|path| path.is_file()) {
+        layers.push(Layer::read(candidate)?);
+    }
+    layers.push(Layer::from_env(host.env)?);
+    if let Some(path) = path {
+        layers.push(Layer::read(Path::new(path))?);
+    }
+    Ok(layers)
+}
+
+/// The parts of a dotted config key, split the way TOML splits them, so
+/// `branch."release/*".strategy` is the `strategy` of the `release/*`
+/// policy.
+fn key_path(key: &str) -> Result<Vec<String>> {
+    let invalid = || {
+        anyhow::anyhow!(
+            "config keys look like `section.name` or `branch.\"<pattern>\".name`, not `{key}`"
+        )
+    };
+    let parsed: Table = format!("{key} = 0").parse().map_err(|_| invalid())?;
+    let mut path = vec![];
+    let mut table = &parsed;
+    loop {
+        let mut entries = table.iter();
+        let (Some((name, value)), None) = (entries.next(), entries.next()) else {
+            return Err(invalid());
+        };
+        path.push(name.clone());
+        match value {
+            toml::Value::Table(inner) => table = inner,
+            _ => return Ok(path),
+        }
+    }
+}
+
+/// Joins key parts back into a dotted key, quoting the parts that aren't
+/// bare TOML keys.
+fn dotted(path: &[impl AsRef<str>]) -> String {
+    let part = |part: &str| {
+        let bare = !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if bare {
+            part.to_string()
+        } else {
+            toml::Value::String(part.to_string()).to_string()
+        }
+    };
+    path.iter()
+        .map(|p| part(p.as_ref()))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// `key` as `config show` and `config get` spell it: `branch.main.push`
+/// and `branch."main".push` are the same key.
+pub fn canonical_key(key: &str) -> Result<String> {
+    Ok(dotted(&key_path(key)?))
+}
+
+/// Sets `key` (`section.name`, or `branch."<pattern>".name` for a branch
+/// policy) to `value` in the config file at `path`, returning the new
+/// contents. The rest of the file, comments included, is kept as it is.
+pub fn set_in_file(path: &Path, key: &str, value: &str) -> Result<String> {
+    let parts = key_path(key)?;
+    let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+    let Some((name, table)) = parts.split_last().filter(|(_, table)| !table.is_empty()) else {
+        anyhow::bail!("config keys look like `section.name`, not `{key}`");
+    };
+    let entry = setting(&parts, value).with_context(|| format!("cannot set {key}"))?;
+    let mut rendered = &toml::Value::Table(entry);
+    for part in &parts {
+        rendered = &rendered[part];
+    }
+    let rendered = rendered.to_string();
+
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("read config {}", path.display())),
+    };
+    let updated = set_line(&text, table, name, &rendered);
+    Layer::parse(&path.display().to_string(), &updated)?;
+    Ok(updated)
+}
+
+/// Replaces `name = ...` under the `[table]` header, or adds it (and the
+/// header).
+fn set_line(text: &str, table: &[&str], name: &str, rendered: &str) -> String {
+    let assignment = format!("{name} = {rendered}");
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    let header = format!("[{}]", dotted(table));
+    // However the file spells it: `[branch."main"]` is `[branch.main]`.
+    let is_header = |line: &str| {
+        line.trim()
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+            .filter(|inner| !inner.starts_with('['))
+            .and_then(|inner| key_path(inner).ok())
+            .is_some_and(|path| path == table)
+    };
+    let Some(start) = lines.iter().position(|line| is_header(line)) else {
+        if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+            lines.push(String::new());
+        }
+        lines.push(header);
+        lines.push(assignment);
+        return lines.join("\n") + "\n";
+    };
+    let end = lines[start + 1..]
+        .iter()
+        .position(|line| line.trim_start().starts_with('['))
+        .map_or(lines.len(), |offset| start + 1 + offset);
+    let existing = (start + 1..end).find(|&i| {
+        lines[i]
+            .split_once('=')
+            .is_some_and(|(key, _)| key.trim() == name)
+    });
+    match existing {
+        Some(i) => lines[i] = assignment,
+        None => {
+            // After the section's last non-blank line.
+            let at = (start + 1..end)
+                .rev()
+                .find(|&i| !lines[i].trim().is_empty())
+                .map_or(start + 1, |i| i + 1);
+            lines.insert(at, assignment);
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct ResolvedConfig {
     pub main: String,
     pub remote: String,
+    /// Remote that pushes go to; `remote` unless set.
+    pub push_remote: String,
+    /// Push the current branch after syncing, as if `--push` were given.
+    pub push: bool,
+    /// Message for merge commits made by the `merge` strategy; `{base}`
+    /// and `{branch}` are filled in.
+    pub merge_message: String,
+    /// Stash uncommitted changes around `sync` instead of refusing to run.
+    pub autostash: bool,
+    /// How fetch and push reach the remote.
+    pub transport: TransportBackend,
+    /// Backups older than this many days are expired; 0 keeps them forever.
+    pub backup_max_age_days: u32,
+    /// Backups kept per branch, newest first; 0 keeps them all.
+    pub backup_max_count: u32,
+    /// Per-branch policies, by glob pattern.
+    pub branches: BTreeMap<String, BranchPolicy>,
+    /// Where each setting came from, by key (`defaults.main`, ...).
+    pub sources: BTreeMap<String, String>,
 }
 
 impl ResolvedConfig {
     pub fn load(path: Option<&str>, repo: &GitRepo, override_main: Option<&str>) -> Result<Self> {
-        let file = path.and_then(|path| std::fs::read_to_string(path).ok());
-        let mut main = None;
-        let mut remote = None;
-        if let Some(s) = file
-            && let Ok(file_config) = toml::from_str::<FileConfig>(&s)
-        {
-            main = file_config
-                .defaults
-                .as_ref()
-                .and_then(|defaults| defaults.main.clone());
-            remote = file_config
-                .defaults
-                .as_ref()
-                .and_then(|defaults| defaults.remote.clone());
-        }
-
-        let remote =
-            remote.unwrap_or_else(|| repo.default_remote().unwrap_or_else(|_| "origin".into()));
-        let main = override_main
-            .map(|s| s.to_string())
-            .or(main)
-            .unwrap_or_else(|| {
-                repo.remote_head_default_branch(&remote)
-                    .unwrap_or_else(|_| "main".into())
-            });
-
-        Ok(Self { main, remote })
+        Self::from_layers(&discover_layers(path, repo)?, repo, override_main)
+    }
+
+    /// Resolves the merged `layers`; command-line flags such as `--main`
+    /// come last and win over all of them.
+    pub fn from_layers(
+        layers: &[Layer],
+        repo: &GitRepo,
+        override_main: Option<&str>,
+    ) -> Result<Self> {
+        let mut merged = Table::new();
+        for layer in layers {
+            merge(&mut merged, layer.table.clone());
+        }
+        let file_config: FileConfig = merged.try_into()?;
+
+        // The last layer to set a key is the one it came from.
+        let mut sources = BTreeMap::new();
+        for layer in layers {
+            for (section, table) in &layer.table {
+                for (key, value) in table.as_table().into_iter().flatten() {
+                    if section == "branch" {
+                        for field in value.as_table().into_iter().flat_map(Table::keys) {
+                            sources.insert(format!("branch.{key:?}.{field}"), layer.source.clone());
+                        }
+                    } else {
+                        sources.insert(format!("{section}.{key}"), layer.source.clone());
+                    }
+                }
+            }
+        }
+        fn fallback(sources: &mut BTreeMap<String, String>, key: &str, source: String) {
+            sources.entry(key.to_string()).or_insert(source);
+        }
+
+        let main = file_config
+            .defaults
+            .as_ref()
+            .and_then(|defaults| defaults.main.clone());
+        let remote = file_config
+            .defaults
+            .as_ref()
+            .and_then(|defaults| defaults.remote.clone());
+        let push_remote = file_config
+            .defaults
+            .as_ref()
+            .and_then(|defaults| defaults.push_remote.clone());
+        let autostash = file_config.sync.as_ref().and_then(|sync| sync.autostash);
+        let push = file_config.sync.as_ref().and_then(|sync| sync.push);
+        let merge_message = file_config
+            .sync
+            .as_ref()
+            .and_then(|sync| sync.merge_message.clone());
+        let transport = file_config
+            .transport
+            .as_ref()
+            .and_then(|transport| transport.backend);
+        let max_age_days = file_config
+            .backup
+            .as_ref()
+            .and_then(|backup| backup.max_age_days);
+        let max_count = file_config
+            .backup
+            .as_ref()
+            .and_then(|backup| backup.max_count);
+
+        // With neither remote configured, a fork checkout fetches from the
+        // repository it was forked from and pushes to the fork.
+        let detected = match (&remote, &push_remote) {
+            (None, None) => detect_fork(&repo.remote_urls()?),
+            _ => None,
+        };
+        let (remote, push_remote) = match detected {
+            Some((fetch, push)) => {
+                let source = format!("detected fork layout (fetch {fetch}, push {push})");
+                fallback(&mut sources, "defaults.remote", source.clone());
+                fallback(&mut sources, "defaults.push_remote", source);
+                (fetch, Some(push))
+            }
+            None => {
+                let remote = remote.unwrap_or_else(|| {
+                    fallback(&mut sources, "defaults.remote", BUILT_IN.to_string());
+                    repo.default_remote().unwrap_or_else(|_| "origin".into())
+                });
+                (remote, push_remote)
+            }
+        };
+        let main = match (override_main, main) {
+            (Some(main), _) => {
+                sources.insert("defaults.main".to_string(), "--main flag".to_string());
+                main.to_string()
+            }
+            (None, Some(main)) => main,
+            (None, None) => match repo.remote_head_default_branch(&remote) {
+                Ok(main) => {
+                    fallback(
+                        &mut sources,
+                        "defaults.main",
+                        format!("refs/remotes/{remote}/HEAD"),
+                    );
+                    main
+                }
+                Err(_) => {
+                    fallback(&mut sources, "defaults.main", BUILT_IN.to_string());
+                    "main".into()
+                }
+            },
+        };
+        let push_remote = push_remote.unwrap_or_else(|| {
+            fallback(
+                &mut sources,
+                "defaults.push_remote",
+                "same as defaults.remote".to_string(),
+            );
+            remote.clone()
+        });
+        fallback(&mut sources, "sync.autostash", BUILT_IN.to_string());
+        fallback(&mut sources, "sync.push", BUILT_IN.to_string());
+        fallback(&mut sources, "sync.merge_message", BUILT_IN.to_string());
+        fallback(&mut sources, "transport.backend", BUILT_IN.to_string());
+        fallback(&mut sources, "backup.max_age_days", BUILT_IN.to_string());
+        fallback(&mut sources, "backup.max_count", BUILT_IN.to_string());
+
+        Ok(Self {
+            main,
+            remote,
+            push_remote,
+            push: push.unwrap_or(false),
+            merge_message: merge_message.unwrap_or_else(|| DEFAULT_MERGE_MESSAGE.to_string()),
+            autostash: autostash.unwrap_or(false),
+            transport: transport.unwrap_or_default(),
+            backup_max_age_days: max_age_days.unwrap_or(DEFAULT_BACKUP_MAX_AGE_DAYS),
+            backup_max_count: max_count.unwrap_or(DEFAULT_BACKUP_MAX_COUNT),
+            branches: file_config.branch.unwrap_or_default(),
+            sources,
+        })
+    }
+
+    /// The fetch remote, then the push remote if it's a different one.
+    pub fn remotes(&self) -> Vec<&str> {
+        let mut remotes = vec![self.remote.as_str()];
+        if self.push_remote != self.remote {
+            remotes.push(self.push_remote.as_str());
+        }
+        remotes
+    }
+
+    /// Every effective setting as `(key, value)`, values rendered as TOML.
+    /// Branch policies are keyed `branch."<glob>".<field>`.
+    pub fn settings(&self) -> Result<Vec<(String, toml::Value)>> {
+        let mut settings: Vec<(String, toml::Value)> = vec![
+            ("defaults.main".into(), self.main.clone().into()),
+            ("defaults.remote".into(), self.remote.clone().into()),
+            (
+                "defaults.push_remote".into(),
+                self.push_remote.clone().into(),
+            ),
+            ("sync.autostash".into(), self.autostash.into()),
+            ("sync.push".into(), self.push.into()),
+            (
+                "sync.merge_message".into(),
+                self.merge_message.clone().into(),
+            ),
+            (
+                "transport.backend".into(),
+                toml::Value::try_from(self.transport)?,
+            ),
+            (
+                "backup.max_age_days".into(),
+                i64::from(self.backup_max_age_days).into(),
+            ),
+            (
+                "backup.max_count".into(),
+                i64::from(self.backup_max_count).into(),
+            ),
+        ];
+        for (pattern, policy) in &self.branches {
+            for (field, value) in toml::Table::try_from(policy)? {
+                settings.push((dotted(&["branch", pattern, &field]), value));
+            }
+        }
+        Ok(settings)
+    }
+
+    /// How long `backups prune` and every applied plan keep backup refs.
+    pub fn backup_expiry(&self) -> Expiry {
+        Expiry {
+            max_age_days: self.backup_max_age_days,
+            max_count: self.backup_max_count,
+        }
+    }
+
+    /// The message for merging `base` into `branch`, from `merge_message`.
+    pub fn merge_commit_message(&self, base: &str, branch: &str) -> String {
+        self.merge_message
+            .replace("{base}", base)
+            .replace("{branch}", branch)
+    }
+
+    /// The policy for `branch`: the section whose pattern matches it most
+    /// specifically (the longest pattern), or an empty one.
+    pub fn policy(&self, branch: &str) -> BranchPolicy {
+        self.branches
+            .iter()
+            .filter(|(pattern, _)| glob_match(pattern, branch))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, policy)| policy.clone())
+            .unwrap_or_default()
     }
 }
 
+const DEFAULT_MERGE_MESSAGE: &str = "Merge branch '{base}' into {branch}";
+
+const DEFAULT_BACKUP_MAX_AGE_DAYS: u32 = 30;
+const DEFAULT_BACKUP_MAX_COUNT: u32 = 20;
+
+/// Source shown for settings nothing configured.
+const BUILT_IN: &str = "built-in default";
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::io::Write;
     use tempfile::NamedTempFile;
 
+    /// No global file, no environment and only the repository's own git
+    /// config, so whatever is configured on this machine can't leak in.
+    fn isolated() -> Host {
+        Host {
+            git_levels: &GIT_LEVELS[3..],
+            global: None,
+            env: vec![],
+        }
+    }
+
+    fn load(
+        path: Option<&str>,
+        repo: &GitRepo,
+        override_main: Option<&str>,
+    ) -> Result<ResolvedConfig> {
+        ResolvedConfig::from_layers(
+            &collect_layers(path, repo, isolated())?,
+            repo,
+            override_main,
+        )
+    }
+
     #[test]
     fn test_file_config_deserialize_empty() {
         let toml_content = "";
@@ -96,6 +835,26 @@ mod tests {
         assert_eq!(defaults.remote, None);
     }
 
+    #[test]
+    fn test_file_config_deserialize_sync_section() {
+        let toml_content = r#"
+                                [sync]
+                                autostash = true
+                                "#;
+        let config: FileConfig = toml::from_str(toml_content).unwrap();
+        assert_eq!(config.sync.unwrap().autostash, Some(true));
+    }
+
+    #[test]
+    fn test_file_config_deserialize_transport_section() {
+        let config: FileConfig = toml::from_str("[transport]\nbackend = \"libgit2\"\n").unwrap();
+        assert_eq!(
+            config.transport.unwrap().backend,
+            Some(TransportBackend::Libgit2)
+        );
+        assert!(toml::from_str::<FileConfig>("[transport]\nbackend = \"ssh\"\n").is_err());
+    }
+
     #[test]
     fn test_resolved_config_with_file() -> Result<()> {
         // Create a temporary config file
@@ -127,8 +886,7 @@ mod tests {
 
         let git_repo = GitRepo { inner: repo };
 
-        let config =
-            ResolvedConfig::load(Some(temp_file.path().to_str().unwrap()), &git_repo, None)?;
+        let config = load(Some(temp_file.path().to_str().unwrap()), &git_repo, None)?;
 
         assert_eq!(config.main, "master");
         assert_eq!(config.remote, "upstream");
@@ -167,7 +925,7 @@ mod tests {
 
         let git_repo = GitRepo { inner: repo };
 
-        let config = ResolvedConfig::load(
+        let config = load(
             Some(temp_file.path().to_str().unwrap()),
             &git_repo,
             Some("override-main"),
@@ -199,13 +957,394 @@ mod tests {
         let git_repo = GitRepo { inner: repo };
 
         // Load with no config file
-        let config = ResolvedConfig::load(None, &git_repo, None)?;
+        let config = load(None, &git_repo, None)?;
 
         // Should fallback to defaults
         assert_eq!(config.remote, "origin");
-        // Main will be either "main" or determined from remote HEAD
-        assert!(!config.main.is_empty());
+        assert!(!config.autostash);
+        assert_eq!(config.main, "main");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_errors_name_file_and_line() {
+        let err = Layer::parse(
+            "repo/.git-helper.toml",
+            "[defaults]\nmain = \"main\"\nmian = \"x\"\n",
+        )
+        .unwrap_err();
+        let message = err.to_string();
+        assert!(message.starts_with("repo/.git-helper.toml:3:"), "{message}");
+        assert!(message.contains("mian"), "{message}");
+
+        let err = Layer::parse("global.toml", "[sync]\nautostash = \n").unwrap_err();
+        assert!(err.to_string().starts_with("global.toml:2:"), "{err}");
+    }
+
+    #[test]
+    fn test_env_layer() -> Result<()> {
+        let vars = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let layer = Layer::from_env(vars(&[
+            ("GIT_HELPER_MAIN", "trunk"),
+            ("GIT_HELPER_AUTOSTASH", "true"),
+            ("GIT_HELPER_TOKEN", "secret"),
+            ("GIT_HELPER_PUSH_REMOTE", "fork"),
+            ("GIT_HELPER_MERGE_MESSAGE", "sync {base}"),
+            ("GIT_HELPER_BACKUP_MAX_COUNT", "5"),
+            ("HOME", "/root"),
+        ]))?;
+        let config: FileConfig = layer.table.try_into()?;
+        let defaults = config.defaults.unwrap();
+        assert_eq!(defaults.main, Some("trunk".to_string()));
+        assert_eq!(defaults.push_remote, Some("fork".to_string()));
+        let sync = config.sync.unwrap();
+        assert_eq!(sync.autostash, Some(true));
+        assert_eq!(sync.merge_message, Some("sync {base}".to_string()));
+        assert_eq!(config.backup.unwrap().max_count, Some(5));
+
+        let err = Layer::from_env(vars(&[("GIT_HELPER_AUTOSTASH", "sometimes")])).unwrap_err();
+        assert!(err.to_string().contains("GIT_HELPER_AUTOSTASH"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_later_layers_win_key_by_key() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let git_repo = GitRepo {
+            inner: git2::Repository::init(temp_dir.path())?,
+        };
+        let layers = vec![
+            Layer::parse(
+                "global",
+                "[defaults]\nmain = \"master\"\nremote = \"upstream\"\n",
+            )?,
+            Layer::parse(
+                "repo",
+                "[defaults]\nremote = \"fork\"\n[sync]\nautostash = true\n",
+            )?,
+        ];
+
+        let config = ResolvedConfig::from_layers(&layers, &git_repo, None)?;
+        assert_eq!(config.main, "master");
+        assert_eq!(config.remote, "fork");
+        assert!(config.autostash);
+
+        let config = ResolvedConfig::from_layers(&layers, &git_repo, Some("trunk"))?;
+        assert_eq!(config.main, "trunk");
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_explicit_config_is_an_error() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let git_repo = GitRepo {
+            inner: git2::Repository::init(temp_dir.path())?,
+        };
+        assert!(
+            collect_layers(Some("/nonexistent/git-helper.toml"), &git_repo, isolated()).is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_layers_come_from_the_given_host() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        let global = home.path().join("config.toml");
+        std::fs::write(
+            &global,
+            "[defaults]\nmain = \"develop\"\nremote = \"fork\"\n",
+        )?;
+        let git_repo = GitRepo {
+            inner: git2::Repository::init(home.path().join("repo"))?,
+        };
+        git_repo.inner.config()?.set_str("githelper.push", "true")?;
+        let host = Host {
+            global: Some(global.clone()),
+            env: vec![("GIT_HELPER_REMOTE".to_string(), "upstream".to_string())],
+            ..isolated()
+        };
+
+        let layers = collect_layers(None, &git_repo, host)?;
+        let config = ResolvedConfig::from_layers(&layers, &git_repo, None)?;
+
+        assert_eq!(config.main, "develop");
+        assert_eq!(config.remote, "upstream");
+        assert!(config.push);
+        assert_eq!(
+            config.sources["defaults.main"],
+            global.display().to_string()
+        );
+        assert_eq!(config.sources["sync.push"], "git config --local");
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_line_keeps_the_rest_of_the_file() {
+        let text = "# mine\n[defaults]\nmain = \"main\" # trunk\n\n[sync]\nautostash = false\n";
+
+        let replaced = set_line(text, &["defaults"], "main", "\"develop\"");
+        assert_eq!(
+            replaced,
+            "# mine\n[defaults]\nmain = \"develop\"\n\n[sync]\nautostash = false\n"
+        );
+
+        let added = set_line(text, &["defaults"], "remote", "\"upstream\"");
+        assert!(added.contains("main = \"main\" # trunk\nremote = \"upstream\"\n\n[sync]"));
+
+        let new_section = set_line(text, &["transport"], "backend", "\"libgit2\"");
+        assert!(new_section.ends_with("autostash = false\n\n[transport]\nbackend = \"libgit2\"\n"));
+    }
+
+    #[test]
+    fn test_set_in_file_rejects_unknown_keys() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("config.toml");
+        assert!(set_in_file(&path, "defaults.mian", "x").is_err());
+        assert!(set_in_file(&path, "sync.autostash", "maybe").is_err());
+        assert_eq!(
+            set_in_file(&path, "sync.autostash", "true")?,
+            "[sync]\nautostash = true\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_in_file_branch_policies() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("config.toml");
+        std::fs::write(&path, "[branch.'release/*']\npush = true\n")?;
+        assert_eq!(
+            set_in_file(&path, "branch.\"release/*\".strategy", "ff-only")?,
+            "[branch.'release/*']\npush = true\nstrategy = \"ff-only\"\n"
+        );
+        assert_eq!(
+            set_in_file(&path, "branch.main.push", "false")?,
+            "[branch.'release/*']\npush = true\n\n[branch.main]\npush = false\n"
+        );
+        assert!(set_in_file(&path, "branch.release/*.strategy", "skip").is_err());
+        assert!(set_in_file(&path, "branch.\"main\".strategy", "squash").is_err());
+        assert!(set_in_file(&path, "branch", "skip").is_err());
+
+        assert_eq!(canonical_key("branch.\"main\".push")?, "branch.main.push");
+        assert_eq!(
+            canonical_key("branch . 'release/*' . push")?,
+            "branch.\"release/*\".push"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_in_file_numbers() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("config.toml");
+        assert_eq!(
+            set_in_file(&path, "backup.max_count", "5")?,
+            "[backup]\nmax_count = 5\n"
+        );
+        assert!(set_in_file(&path, "backup.max_age_days", "soon").is_err());
+        assert_eq!(
+            set_in_file(&path, "defaults.main", "2024")?,
+            "[defaults]\nmain = \"2024\"\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sources_track_the_winning_layer() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let git_repo = GitRepo {
+            inner: git2::Repository::init(temp_dir.path())?,
+        };
+        let layers = vec![
+            Layer::parse("global", "[defaults]\nremote = \"upstream\"\n")?,
+            Layer::parse("local", "[defaults]\nremote = \"fork\"\n")?,
+        ];
+
+        let config = ResolvedConfig::from_layers(&layers, &git_repo, Some("trunk"))?;
+        assert_eq!(config.sources["defaults.remote"], "local");
+        assert_eq!(config.sources["defaults.main"], "--main flag");
+        assert_eq!(config.sources["sync.autostash"], BUILT_IN);
+        Ok(())
+    }
+
+    #[test]
+    fn test_git_config_layer() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("gitconfig");
+        std::fs::write(
+            &path,
+            "[checkout]\n\tdefaultRemote = upstream\n\
+             [remote]\n\tpushDefault = fork\n\
+             [githelper]\n\tmain = trunk\n\tpush = yes\n",
+        )?;
+        let git_repo = GitRepo {
+            inner: git2::Repository::init(temp_dir.path().join("repo"))?,
+        };
+
+        let layer = Layer::from_git_config("git config --global", &git2::Config::open(&path)?)?;
+        let config = ResolvedConfig::from_layers(&[layer], &git_repo, None)?;
+        assert_eq!(config.main, "trunk");
+        assert_eq!(config.remote, "upstream");
+        assert_eq!(config.push_remote, "fork");
+        assert!(config.push);
+        assert_eq!(config.sources["defaults.remote"], "git config --global");
+
+        // githelper.remote wins over checkout.defaultRemote.
+        std::fs::write(
+            &path,
+            "[checkout]\n\tdefaultRemote = upstream\n[githelper]\n\tremote = mine\n",
+        )?;
+        let layer = Layer::from_git_config("git config --local", &git2::Config::open(&path)?)?;
+        let config = ResolvedConfig::from_layers(&[layer], &git_repo, None)?;
+        assert_eq!(config.remote, "mine");
+        assert_eq!(config.push_remote, "mine");
+
+        std::fs::write(&path, "[githelper]\n\ttransport = ssh\n")?;
+        let err =
+            Layer::from_git_config("git config --local", &git2::Config::open(&path)?).unwrap_err();
+        assert!(format!("{err:#}").contains("githelper.transport"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("release/*", "release/1.2"));
+        assert!(!glob_match("release/*", "release/1.2/hotfix"));
+        assert!(glob_match("release/**", "release/1.2/hotfix"));
+        assert!(glob_match("feature/?-*", "feature/x-login"));
+        assert!(!glob_match("feature/*", "feature"));
+        assert!(glob_match("main", "main"));
+        assert!(!glob_match("main", "maintenance"));
+    }
+
+    #[test]
+    fn test_branch_policies() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let git_repo = GitRepo {
+            inner: git2::Repository::init(temp_dir.path())?,
+        };
+        let layer = Layer::parse(
+            "repo",
+            r#"
+            [branch."release/*"]
+            strategy = "ff-only"
+            push = false
+
+            [branch."release/legacy-*"]
+            strategy = "skip"
+
+            [branch."feature/**"]
+            base = "develop"
+            force_with_lease = false
+            "#,
+        )?;
+
+        let config = ResolvedConfig::from_layers(&[layer], &git_repo, None)?;
+        assert_eq!(
+            config.policy("release/2.0").strategy,
+            Some(Strategy::FfOnly)
+        );
+        assert_eq!(config.policy("release/2.0").push, Some(false));
+        assert_eq!(
+            config.policy("release/legacy-1").strategy,
+            Some(Strategy::Skip)
+        );
+        assert_eq!(
+            config.policy("feature/a/b").base.as_deref(),
+            Some("develop")
+        );
+        assert_eq!(config.policy("topic"), BranchPolicy::default());
+        assert_eq!(config.sources[r#"branch."release/*".strategy"#], "repo");
+        assert!(
+            config
+                .settings()?
+                .iter()
+                .any(|(key, _)| key == r#"branch."feature/**".force_with_lease"#)
+        );
+
+        assert!(Layer::parse("repo", "[branch.\"x\"]\nstrategy = \"squash\"\n").is_err());
+        assert!(Layer::parse("repo", "[branch.\"x\"]\npsuh = true\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_repo_slug() {
+        let slug = |url| repo_slug(url).map(|(h, o, r)| format!("{h} {o} {r}"));
+        assert_eq!(
+            slug("git@github.com:me/tool.git").as_deref(),
+            Some("github.com me tool")
+        );
+        assert_eq!(
+            slug("https://user@GitHub.com/org/tool/").as_deref(),
+            Some("github.com org tool")
+        );
+        assert_eq!(
+            slug("ssh://git@host:2222/group/sub/tool.git").as_deref(),
+            Some("host group/sub tool")
+        );
+        assert_eq!(slug("/srv/git/tool.git").as_deref(), Some(" /srv/git tool"));
+        assert_eq!(slug("tool"), None);
+    }
+
+    #[test]
+    fn test_detect_fork() {
+        let remotes = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(name, url)| (name.to_string(), url.to_string()))
+                .collect()
+        };
+        let fork = remotes(&[
+            ("origin", "git@github.com:me/tool.git"),
+            ("upstream", "https://github.com/org/tool"),
+        ]);
+        assert_eq!(
+            detect_fork(&fork),
+            Some(("upstream".to_string(), "origin".to_string()))
+        );
+
+        let other_name = remotes(&[
+            ("origin", "git@github.com:me/tool.git"),
+            ("org", "git@github.com:org/tool.git"),
+            ("docs", "git@github.com:org/docs.git"),
+        ]);
+        assert_eq!(
+            detect_fork(&other_name),
+            Some(("org".to_string(), "origin".to_string()))
+        );
+
+        let unrelated = remotes(&[
+            ("origin", "git@github.com:me/tool.git"),
+            ("mirror", "git@gitlab.com:me/tool.git"),
+        ]);
+        assert_eq!(detect_fork(&unrelated), None);
+        assert_eq!(detect_fork(&fork[1..]), None);
+    }
+
+    #[test]
+    fn test_fork_layout_is_detected_unless_configured() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let repo = git2::Repository::init(temp_dir.path())?;
+        repo.remote("origin", "git@github.com:me/tool.git")?;
+        repo.remote("upstream", "git@github.com:org/tool.git")?;
+        let git_repo = GitRepo { inner: repo };
+
+        let config = ResolvedConfig::from_layers(&[], &git_repo, None)?;
+        assert_eq!(config.remote, "upstream");
+        assert_eq!(config.push_remote, "origin");
+        assert_eq!(config.remotes(), ["upstream", "origin"]);
+        assert!(config.sources["defaults.remote"].starts_with("detected fork layout"));
 
+        let layer = Layer::parse("local", "[defaults]\nremote = \"origin\"\n")?;
+        let config = ResolvedConfig::from_layers(&[layer], &git_repo, None)?;
+        assert_eq!(config.push_remote, "origin");
+        assert_eq!(config.remotes(), ["origin"]);
         Ok(())
     }
 }
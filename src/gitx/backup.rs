@@ -0,0 +1,224 @@
+use anyhow::{Context, Result};
+use git2::{Oid, Repository};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Namespace that backups of rewritten branch tips are kept under.
+pub const BACKUP_PREFIX: &str = "refs/git-helper/backup";
+
+/// A saved branch tip, `refs/git-helper/backup/<time>/<branch>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backup {
+    pub name: String,
+    pub branch: String,
+    /// When it was taken, in seconds since the Unix epoch.
+    pub time: u64,
+    pub tip: Oid,
+}
+
+/// How long backups are kept. A limit of 0 is no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expiry {
+    pub max_age_days: u32,
+    /// Backups kept per branch, newest first.
+    pub max_count: u32,
+}
+
+pub fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Saves `tip` as `refs/git-helper/backup/<unix time>/<branch>` before
+/// `branch` is rewritten, and returns the ref name. A second backup of the
+/// same branch within one second takes the next free second, so none is
+/// ever overwritten.
+pub fn create(repo: &Repository, branch: &str, tip: Oid) -> Result<String> {
+    let mut stamp = now()?;
+    loop {
+        let name = format!("{BACKUP_PREFIX}/{stamp}/{branch}");
+        match super::refs::ref_target(repo, &name)? {
+            Some(existing) if existing == tip => return Ok(name),
+            Some(_) => stamp += 1,
+            None => {
+                repo.reference(&name, tip, false, &format!("backup of {branch}"))?;
+                return Ok(name);
+            }
+        }
+    }
+}
+
+/// Every backup, oldest first.
+pub fn list(repo: &Repository) -> Result<Vec<Backup>> {
+    let mut backups = vec![];
+    for reference in repo.references_glob(&format!("{BACKUP_PREFIX}/*"))? {
+        let reference = reference?;
+        let (Some(name), Some(tip)) = (reference.name(), reference.target()) else {
+            continue;
+        };
+        let Some((stamp, branch)) = name
+            .strip_prefix(&format!("{BACKUP_PREFIX}/"))
+            .and_then(|rest| rest.split_once('/'))
+        else {
+            continue;
+        };
+        let Ok(time) = stamp.parse() else { continue };
+        backups.push(Backup {
+            name: name.to_string(),
+            branch: branch.to_string(),
+            time,
+            tip,
+        });
+    }
+    backups.sort_by(|a, b| (a.time, &a.branch).cmp(&(b.time, &b.branch)));
+    Ok(backups)
+}
+
+/// Finds a backup by ref name, by `<time>/<branch>`, or by branch name for
+/// that branch's newest backup.
+pub fn find(repo: &Repository, spec: &str) -> Result<Backup> {
+    let backups = list(repo)?;
+    let spec = spec
+        .strip_prefix(&format!("{BACKUP_PREFIX}/"))
+        .unwrap_or(spec);
+    let exact = backups
+        .iter()
+        .find(|b| format!("{}/{}", b.time, b.branch) == spec);
+    let newest = || backups.iter().rev().find(|b| b.branch == spec);
+    exact
+        .or_else(newest)
+        .cloned()
+        .with_context(|| format!("no backup matches {spec}; see `git-helper backups list`"))
+}
+
+/// The backups `expiry` no longer keeps at time `now`, oldest first.
+pub fn expired(backups: &[Backup], expiry: Expiry, now: u64) -> Vec<Backup> {
+    let max_age = u64::from(expiry.max_age_days) * 24 * 60 * 60;
+    let mut newer: BTreeMap<&str, u32> = BTreeMap::new();
+    let mut expired = vec![];
+    for backup in backups.iter().rev() {
+        let rank = newer.entry(&backup.branch).or_default();
+        let too_many = expiry.max_count != 0 && *rank >= expiry.max_count;
+        let too_old = expiry.max_age_days != 0 && now.saturating_sub(backup.time) > max_age;
+        *rank += 1;
+        if too_many || too_old {
+            expired.push(backup.clone());
+        }
+    }
+    expired.reverse();
+    expired
+}
+
+pub fn delete(repo: &Repository, backup: &Backup) -> Result<()> {
+    repo.find_reference(&backup.name)?
+        .delete()
+        .with_context(|| format!("delete {}", backup.name))
+}
+
+/// Points the backup's branch back at the saved tip, backing up where it
+/// is now first (so a restore can be undone the same way). Returns that
+/// new backup, or `None` when the branch was already there or missing.
+pub fn restore(repo: &Repository, backup: &Backup) -> Result<Option<String>> {
+    let refname = format!("refs/heads/{}", backup.branch);
+    let current = super::refs::ref_target(repo, &refname)?;
+    if current == Some(backup.tip) {
+        return Ok(None);
+    }
+    super::refs::check_movable(repo, &backup.branch)?;
+    let saved = match current {
+        Some(tip) => Some(create(repo, &backup.branch, tip)?),
+        None => None,
+    };
+    super::refs::move_branch(
+        repo,
+        &backup.branch,
+        backup.tip,
+        &format!("restore {} from {}", backup.branch, backup.name),
+    )?;
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitx::test_support::TestRepo;
+
+    fn backup(branch: &str, time: u64) -> Backup {
+        Backup {
+            name: format!("{BACKUP_PREFIX}/{time}/{branch}"),
+            branch: branch.to_string(),
+            time,
+            tip: Oid::zero(),
+        }
+    }
+
+    #[test]
+    fn test_expired_by_age_and_count() {
+        let day = 24 * 60 * 60;
+        let backups = [
+            backup("main", 0),
+            backup("feature", day),
+            backup("main", 5 * day),
+            backup("main", 9 * day),
+        ];
+        let names = |expiry| -> Vec<String> {
+            expired(&backups, expiry, 10 * day)
+                .into_iter()
+                .map(|b| b.name)
+                .collect()
+        };
+
+        assert!(
+            names(Expiry {
+                max_age_days: 0,
+                max_count: 0
+            })
+            .is_empty()
+        );
+        assert_eq!(
+            names(Expiry {
+                max_age_days: 7,
+                max_count: 0
+            }),
+            [backups[0].name.clone(), backups[1].name.clone()]
+        );
+        assert_eq!(
+            names(Expiry {
+                max_age_days: 0,
+                max_count: 2
+            }),
+            [backups[0].name.clone()]
+        );
+    }
+
+    #[test]
+    fn test_create_list_find_and_restore() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let repo = &test_repo.repo;
+        let old = test_repo.commit_file("file.txt", "one\n", "one")?;
+        let first = create(repo, "main", old)?;
+        assert_eq!(create(repo, "main", old)?, first);
+        let new = test_repo.commit_file("file.txt", "two\n", "two")?;
+        let second = create(repo, "main", new)?;
+        assert_ne!(second, first);
+
+        let backups = list(repo)?;
+        assert_eq!(
+            backups.iter().map(|b| &b.name).collect::<Vec<_>>(),
+            [&first, &second]
+        );
+        assert_eq!(find(repo, "main")?.name, second);
+        assert_eq!(find(repo, &first)?.tip, old);
+        assert!(find(repo, "feature").is_err());
+
+        let saved = restore(repo, &find(repo, &first)?)?;
+        assert_eq!(test_repo.tip("main")?, old);
+        assert_eq!(
+            std::fs::read_to_string(test_repo.path().join("file.txt"))?,
+            "one\n"
+        );
+        let saved = saved.expect("the rewound tip is backed up");
+        assert_eq!(crate::gitx::refs::ref_target(repo, &saved)?, Some(new));
+        assert_eq!(restore(repo, &find(repo, &first)?)?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+
+/// Failures scripts need to tell apart; `main` maps each one to its own
+/// exit code. Anything else stays a plain `anyhow` error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GitxError {
+    NotARepo {
+        path: String,
+    },
+    DetachedHead,
+    MissingUpstream {
+        remote: String,
+        branch: String,
+    },
+    Diverged {
+        remote: String,
+        branch: String,
+        ahead: usize,
+        behind: usize,
+    },
+    /// A rebase or merge stopped because `commit` doesn't apply cleanly.
+    Conflict {
+        commit: String,
+        paths: Vec<String>,
+    },
+    DirtyWorktree {
+        path: String,
+    },
+    AuthFailed {
+        remote: String,
+        message: String,
+    },
+    PushRejected {
+        remote: String,
+        branch: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for GitxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GitxError::NotARepo { path } => write!(f, "{path} is not inside a git repository"),
+            GitxError::DetachedHead => write!(f, "HEAD is detached; check out a branch first"),
+            GitxError::MissingUpstream { remote, branch } => {
+                write!(f, "no {remote}/{branch} to sync from")
+            }
+            GitxError::Diverged {
+                remote,
+                branch,
+                ahead,
+                behind,
+            } => write!(
+                f,
+                "{branch} has diverged from {remote}/{branch} ({ahead} ahead, {behind} behind)"
+            ),
+            GitxError::Conflict { commit, paths } => {
+                write!(f, "{commit} conflicts in {}", paths.join(", "))
+            }
+            GitxError::DirtyWorktree { path } => {
+                write!(f, "uncommitted changes in {path}")
+            }
+            GitxError::AuthFailed { remote, message } => {
+                write!(f, "authentication to {remote} failed: {message}")
+            }
+            GitxError::PushRejected {
+                remote,
+                branch,
+                message,
+            } => write!(f, "{remote} rejected the push of {branch}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for GitxError {}
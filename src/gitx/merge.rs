@@ -0,0 +1,271 @@
+use super::GitxError;
+use super::rebase::{conflicted_paths, take_theirs};
+use anyhow::{Context, Result};
+use git2::build::CheckoutBuilder;
+use git2::{BranchType, MergeOptions, Oid, Repository, RepositoryState, ResetType};
+
+/// Result of merging another branch into a branch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeOutcome {
+    /// A merge commit was created.
+    Merged { commit: Oid },
+    /// The branch already contains everything being merged.
+    UpToDate,
+    /// The merge of `commit` into the checked-out branch stopped on
+    /// conflicts. The in-progress state is left on disk for
+    /// `--continue`/`--abort`.
+    Conflicted { commit: Oid, paths: Vec<String> },
+    /// A merge into a branch that isn't checked out would conflict; nothing
+    /// was changed.
+    Abandoned { paths: Vec<String> },
+}
+
+/// Merges the local branch `from` into `branch` with a merge commit. The
+/// checked-out branch is merged in the worktree and may stop on conflicts;
+/// any other branch is merged in memory, so it never touches the worktree.
+pub fn merge_from(
+    repo: &Repository,
+    branch: &str,
+    from: &str,
+    message: &str,
+) -> Result<MergeOutcome> {
+    let refname = format!("refs/heads/{branch}");
+    let ours = repo.find_reference(&refname)?.peel_to_commit()?;
+    let theirs = repo
+        .find_branch(from, BranchType::Local)?
+        .get()
+        .peel_to_commit()?;
+    if ours.id() == theirs.id() || repo.graph_descendant_of(ours.id(), theirs.id())? {
+        return Ok(MergeOutcome::UpToDate);
+    }
+
+    if repo.head()?.name() != Some(&refname) || repo.head_detached()? {
+        if let Some(checkout) = super::refs::checkout_of(repo, &refname)? {
+            let path = checkout.workdir().map(|p| p.display().to_string());
+            anyhow::bail!(
+                "{branch} is checked out in {}; merge it there",
+                path.unwrap_or_default()
+            );
+        }
+        let mut index = repo
+            .merge_commits(&ours, &theirs, Some(&MergeOptions::new()))
+            .with_context(|| format!("merge {from} into {branch}"))?;
+        if index.has_conflicts() {
+            return Ok(MergeOutcome::Abandoned {
+                paths: conflicted_paths(&index)?,
+            });
+        }
+        let tree = repo.find_tree(index.write_tree_to(repo)?)?;
+        let sig = repo.signature()?;
+        let commit = repo.commit(None, &sig, &sig, message, &tree, &[&ours, &theirs])?;
+        repo.reference(&refname, commit, true, "merge (git-helper): finished")?;
+        return Ok(MergeOutcome::Merged { commit });
+    }
+
+    let annotated = repo.find_annotated_commit(theirs.id())?;
+    let mut checkout = CheckoutBuilder::new();
+    checkout
+        .safe()
+        .allow_conflicts(true)
+        .conflict_style_merge(true);
+    repo.merge(&[&annotated], None, Some(&mut checkout))
+        .with_context(|| format!("merge {from} into {branch}"))?;
+    // Keep our message for `--continue` instead of libgit2's default.
+    std::fs::write(repo.path().join("MERGE_MSG"), format!("{message}\n"))?;
+    let index = repo.index()?;
+    if index.has_conflicts() {
+        return Ok(MergeOutcome::Conflicted {
+            commit: theirs.id(),
+            paths: conflicted_paths(&index)?,
+        });
+    }
+    let commit = commit_merge(repo)?;
+    Ok(MergeOutcome::Merged { commit })
+}
+
+/// Merges `theirs` into `ours` in the object database only, returning the
+/// would-be merge commit and the paths that would conflict, if any.
+pub fn simulate_merge(
+    repo: &Repository,
+    ours: Oid,
+    theirs: Oid,
+    message: &str,
+) -> Result<(Oid, Option<Vec<String>>)> {
+    let ours = repo.find_commit(ours)?;
+    let theirs = repo.find_commit(theirs)?;
+    let mut index = repo.merge_commits(&ours, &theirs, None)?;
+    let mut conflicts = None;
+    if index.has_conflicts() {
+        conflicts = Some(conflicted_paths(&index)?);
+        take_theirs(&mut index)?;
+    }
+    let tree = repo.find_tree(index.write_tree_to(repo)?)?;
+    let sig = ours.committer();
+    let commit = repo.commit(None, &sig, &sig, message, &tree, &[&ours, &theirs])?;
+    Ok((commit, conflicts))
+}
+
+pub fn in_progress(repo: &Repository) -> bool {
+    repo.state() == RepositoryState::Merge
+}
+
+/// Commits a stopped merge once its conflicts are resolved and staged.
+pub fn continue_merge(repo: &Repository) -> Result<MergeOutcome> {
+    if !in_progress(repo) {
+        anyhow::bail!("no merge in progress");
+    }
+    let index = repo.index()?;
+    if index.has_conflicts() {
+        return Err(GitxError::Conflict {
+            commit: merge_head(repo)?.to_string(),
+            paths: conflicted_paths(&index)?,
+        })
+        .context("unresolved conflicts remain; resolve and stage them first");
+    }
+    let commit = commit_merge(repo)?;
+    Ok(MergeOutcome::Merged { commit })
+}
+
+/// Throws away a stopped merge, restoring the branch and worktree.
+pub fn abort_merge(repo: &Repository) -> Result<()> {
+    if !in_progress(repo) {
+        anyhow::bail!("no merge in progress");
+    }
+    let head = repo.head()?.peel(git2::ObjectType::Commit)?;
+    repo.reset(&head, ResetType::Hard, Some(CheckoutBuilder::new().force()))?;
+    repo.cleanup_state()?;
+    Ok(())
+}
+
+/// Commits the index as a merge of HEAD and MERGE_HEAD, using MERGE_MSG.
+fn commit_merge(repo: &Repository) -> Result<Oid> {
+    let mut index = repo.index()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let head = repo.head()?.peel_to_commit()?;
+    let theirs = repo.find_commit(merge_head(repo)?)?;
+    let message = repo.message().context("read MERGE_MSG")?;
+    let sig = repo.signature()?;
+    let commit = repo
+        .commit(
+            Some("HEAD"),
+            &sig,
+            &sig,
+            message.trim_end(),
+            &tree,
+            &[&head, &theirs],
+        )
+        .context("commit merge")?;
+    repo.cleanup_state()?;
+    Ok(commit)
+}
+
+fn merge_head(repo: &Repository) -> Result<Oid> {
+    repo.refname_to_id("MERGE_HEAD").context("no MERGE_HEAD")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitx::test_support::TestRepo;
+
+    /// `feature` and `main` both edited `file.txt`; `conflict` decides
+    /// whether they touched the same line.
+    fn diverged(test_repo: &TestRepo, conflict: bool) -> Result<()> {
+        test_repo.commit_file("file.txt", "base\n", "base")?;
+        test_repo.branch("feature")?;
+        test_repo.commit_file("main.txt", "main\n", "main change")?;
+        if conflict {
+            test_repo.commit_file("file.txt", "main\n", "main edit")?;
+        }
+        test_repo.checkout("feature")?;
+        test_repo.commit_file("file.txt", "feature\n", "feature change")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_creates_merge_commit() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        diverged(&test_repo, false)?;
+        let before = test_repo.tip("feature")?;
+
+        let outcome = merge_from(&test_repo.repo, "feature", "main", "Merge main")?;
+
+        let tip = test_repo.repo.find_commit(test_repo.tip("feature")?)?;
+        assert_eq!(outcome, MergeOutcome::Merged { commit: tip.id() });
+        assert_eq!(tip.message(), Some("Merge main"));
+        assert_eq!(
+            tip.parent_ids().collect::<Vec<_>>(),
+            [before, test_repo.tip("main")?]
+        );
+        assert!(!in_progress(&test_repo.repo));
+        assert!(test_repo.path().join("main.txt").exists());
+        assert_eq!(
+            merge_from(&test_repo.repo, "feature", "main", "again")?,
+            MergeOutcome::UpToDate
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_merge_reports_conflicts() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        diverged(&test_repo, true)?;
+        let before = test_repo.tip("feature")?;
+
+        let (_, conflicts) =
+            simulate_merge(&test_repo.repo, before, test_repo.tip("main")?, "Merge")?;
+
+        assert_eq!(conflicts, Some(vec!["file.txt".to_string()]));
+        assert_eq!(test_repo.tip("feature")?, before);
+        assert!(!in_progress(&test_repo.repo));
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_merge_leaves_worktree_alone() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        diverged(&test_repo, false)?;
+        test_repo.checkout("main")?;
+
+        let outcome = merge_from(&test_repo.repo, "feature", "main", "Merge main")?;
+
+        assert!(matches!(outcome, MergeOutcome::Merged { .. }));
+        assert_eq!(test_repo.repo.head()?.shorthand(), Some("main"));
+        let tip = test_repo.repo.find_commit(test_repo.tip("feature")?)?;
+        assert_eq!(tip.parent_count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicted_merge_continue_and_abort() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        diverged(&test_repo, true)?;
+        let before = test_repo.tip("feature")?;
+
+        let outcome = merge_from(&test_repo.repo, "feature", "main", "Merge main")?;
+        assert_eq!(
+            outcome,
+            MergeOutcome::Conflicted {
+                commit: test_repo.tip("main")?,
+                paths: vec!["file.txt".to_string()]
+            }
+        );
+        assert!(in_progress(&test_repo.repo));
+        assert!(continue_merge(&test_repo.repo).is_err());
+
+        abort_merge(&test_repo.repo)?;
+        assert!(!in_progress(&test_repo.repo));
+        assert_eq!(test_repo.tip("feature")?, before);
+
+        merge_from(&test_repo.repo, "feature", "main", "Merge main")?;
+        test_repo.stage_file("file.txt", "resolved\n")?;
+        let MergeOutcome::Merged { commit } = continue_merge(&test_repo.repo)? else {
+            panic!("expected a merge commit");
+        };
+        let commit = test_repo.repo.find_commit(commit)?;
+        assert_eq!(commit.message(), Some("Merge main"));
+        assert_eq!(commit.parent_id(1)?, test_repo.tip("main")?);
+        assert!(!in_progress(&test_repo.repo));
+        Ok(())
+    }
+}
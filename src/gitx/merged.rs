@@ -0,0 +1,239 @@
+use anyhow::Result;
+use git2::{Commit, ErrorCode, Oid, Repository};
+use std::collections::HashMap;
+
+/// Patch id of the change from commit `from` to commit `to`, or `None` when
+/// the trees are identical. Equal ids mean the same change, whatever the
+/// commit metadata (like `git patch-id`).
+pub fn patch_id(repo: &Repository, from: Oid, to: Oid) -> Result<Option<Oid>> {
+    let from = repo.find_commit(from)?.tree()?;
+    let to = repo.find_commit(to)?.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&from), Some(&to), None)?;
+    if diff.deltas().len() == 0 {
+        return Ok(None);
+    }
+    Ok(Some(diff.patchid(None)?))
+}
+
+/// Patch ids of commits, each computed at most once. Checking every branch
+/// against main walks main's recent commits again and again.
+#[derive(Debug, Default)]
+pub struct PatchIds(HashMap<Oid, Option<Oid>>);
+
+impl PatchIds {
+    /// Patch ids of the single-parent commits in `base..tip`.
+    pub fn between(&mut self, repo: &Repository, base: Oid, tip: Oid) -> Result<Vec<Oid>> {
+        let mut walk = repo.revwalk()?;
+        walk.push(tip)?;
+        walk.hide(base)?;
+        let mut ids = vec![];
+        for oid in walk {
+            if let Some(id) = self.of(repo, &repo.find_commit(oid?)?)? {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Patch id of a single-parent commit's change, `None` for merges,
+    /// root commits and empty commits.
+    fn of(&mut self, repo: &Repository, commit: &Commit) -> Result<Option<Oid>> {
+        if let Some(id) = self.0.get(&commit.id()) {
+            return Ok(*id);
+        }
+        let id = if commit.parent_count() == 1 {
+            patch_id(repo, commit.parent_id(0)?, commit.id())?
+        } else {
+            None
+        };
+        self.0.insert(commit.id(), id);
+        Ok(id)
+    }
+}
+
+/// The best common ancestor of `a` and `b`, or `None` when their histories
+/// share no commit (an orphan branch like `gh-pages`).
+fn merge_base(repo: &Repository, a: Oid, b: Oid) -> Result<Option<Oid>> {
+    match repo.merge_base(a, b) {
+        Ok(base) => Ok(Some(base)),
+        Err(err) if err.code() == ErrorCode::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Commits in `tip`'s history since it forked from `onto` whose change
+/// `onto` already has, e.g. cherry-picked or merged as-is (the commits
+/// `git cherry onto tip` marks with `-`), oldest first.
+pub fn upstream_equivalents(repo: &Repository, onto: Oid, tip: Oid) -> Result<Vec<Oid>> {
+    let Some(base) = merge_base(repo, onto, tip)? else {
+        return Ok(vec![]);
+    };
+    let mut known = PatchIds::default();
+    let upstream = known.between(repo, base, onto)?;
+    if upstream.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut walk = repo.revwalk()?;
+    walk.push(tip)?;
+    walk.hide(base)?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    let mut equivalents = vec![];
+    for oid in walk {
+        let commit = repo.find_commit(oid?)?;
+        if known
+            .of(repo, &commit)?
+            .is_some_and(|id| upstream.contains(&id))
+        {
+            equivalents.push(commit.id());
+        }
+    }
+    Ok(equivalents)
+}
+
+/// Commits reachable from `tip` but not from `upstream`, oldest first.
+pub fn local_only(repo: &Repository, upstream: Oid, tip: Oid) -> Result<Vec<Oid>> {
+    let mut walk = repo.revwalk()?;
+    walk.push(tip)?;
+    walk.hide(upstream)?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    Ok(walk.collect::<Result<_, _>>()?)
+}
+
+/// Whether everything on `branch` is already reachable from `main`.
+pub fn is_merged(repo: &Repository, branch: Oid, main: Oid) -> Result<bool> {
+    Ok(branch == main || repo.graph_descendant_of(main, branch)?)
+}
+
+/// Whether `branch` is reachable from one of `remote`'s branches, so its
+/// commits survive on the remote even if its own branch there is gone.
+pub fn is_on_remote(repo: &Repository, branch: Oid, remote: &str) -> Result<bool> {
+    for reference in repo.references_glob(&format!("refs/remotes/{remote}/*"))? {
+        if let Some(target) = reference?.target()
+            && is_merged(repo, branch, target)?
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether `main` gained a single commit carrying the branch's combined
+/// change since they forked, i.e. the branch was squash-merged. `main_ids`
+/// keeps main's patch ids between calls.
+pub fn is_squash_merged(
+    repo: &Repository,
+    branch: Oid,
+    main: Oid,
+    main_ids: &mut PatchIds,
+) -> Result<bool> {
+    let Some(base) = merge_base(repo, branch, main)? else {
+        return Ok(false);
+    };
+    if base == branch {
+        return Ok(false);
+    }
+    let Some(combined) = patch_id(repo, base, branch)? else {
+        return Ok(false);
+    };
+    Ok(main_ids.between(repo, base, main)?.contains(&combined))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitx::test_support::TestRepo;
+
+    #[test]
+    fn test_squash_merge_detected() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("base.txt", "base\n", "base")?;
+        test_repo.branch("feature")?;
+        test_repo.commit_file("other.txt", "other\n", "unrelated main work")?;
+        test_repo.checkout("feature")?;
+        test_repo.commit_file("a.txt", "a\n", "a")?;
+        test_repo.commit_file("a.txt", "a2\n", "a again")?;
+        let feature = test_repo.tip("feature")?;
+        test_repo.checkout("main")?;
+        let main_before = test_repo.tip("main")?;
+        assert!(!is_squash_merged(
+            &test_repo.repo,
+            feature,
+            main_before,
+            &mut PatchIds::default()
+        )?);
+
+        test_repo.commit_file("a.txt", "a2\n", "squashed feature")?;
+        let main = test_repo.tip("main")?;
+
+        assert!(is_squash_merged(
+            &test_repo.repo,
+            feature,
+            main,
+            &mut PatchIds::default()
+        )?);
+        assert!(!is_merged(&test_repo.repo, feature, main)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cherry_picked_commits_are_upstream_equivalents() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("base.txt", "base\n", "base")?;
+        test_repo.branch("feature")?;
+        test_repo.checkout("feature")?;
+        let picked = test_repo.commit_file("a.txt", "a\n", "a")?;
+        test_repo.commit_file("b.txt", "b\n", "b")?;
+        let feature = test_repo.tip("feature")?;
+        test_repo.checkout("main")?;
+        let main = test_repo.tip("main")?;
+        assert!(upstream_equivalents(&test_repo.repo, main, feature)?.is_empty());
+
+        test_repo.commit_file("other.txt", "other\n", "other")?;
+        test_repo.commit_file("a.txt", "a\n", "a, cherry-picked")?;
+        let main = test_repo.tip("main")?;
+
+        assert_eq!(
+            upstream_equivalents(&test_repo.repo, main, feature)?,
+            vec![picked]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merged_branch() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let base = test_repo.commit_file("base.txt", "base\n", "base")?;
+        let main = test_repo.commit_file("a.txt", "a\n", "a")?;
+
+        assert!(is_merged(&test_repo.repo, base, main)?);
+        assert!(!is_merged(&test_repo.repo, main, base)?);
+        assert!(!is_squash_merged(
+            &test_repo.repo,
+            base,
+            main,
+            &mut PatchIds::default()
+        )?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_orphan_branch_shares_nothing_with_main() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let main = test_repo.commit_file("base.txt", "base\n", "base")?;
+        let signature = test_repo.repo.signature()?;
+        let tree = test_repo.repo.find_commit(main)?.tree()?;
+        let orphan = test_repo
+            .repo
+            .commit(None, &signature, &signature, "orphan", &tree, &[])?;
+
+        let mut main_ids = PatchIds::default();
+        assert!(!is_squash_merged(
+            &test_repo.repo,
+            orphan,
+            main,
+            &mut main_ids
+        )?);
+        assert!(upstream_equivalents(&test_repo.repo, main, orphan)?.is_empty());
+        Ok(())
+    }
+}
@@ -1,6 +1,21 @@
+mod backup;
+mod error;
+mod merge;
+mod merged;
 mod rebase;
 mod refs;
 mod remote;
 mod repo;
+mod stash;
+#[cfg(test)]
+pub(crate) mod test_support;
 
+pub use backup::{Backup, Expiry};
+pub use error::GitxError;
+pub use merge::MergeOutcome;
+pub use merged::PatchIds;
+pub use rebase::{RebaseOutcome, SimulatedRebase};
+pub use refs::BranchStatus;
+pub use remote::TransportBackend;
 pub use repo::{GitRepo, OpenRepoOpts};
+pub use stash::UnstashOutcome;
@@ -1,27 +1,703 @@
+use super::GitxError;
 use anyhow::{Context, Result};
-use git2::{BranchType, Repository};
+use git2::build::CheckoutBuilder;
+use git2::{BranchType, ErrorCode, Oid, Rebase, RebaseOptions, Repository, RepositoryState};
 
+/// Result of driving a rebase as far as it will go without user input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RebaseOutcome {
+    Completed,
+    /// The rebase stopped because `commit` did not apply cleanly. The
+    /// in-progress state is left on disk for `--continue`/`--skip`/`--abort`.
+    Conflicted {
+        commit: Oid,
+        paths: Vec<String>,
+    },
+    /// An in-memory rebase of a branch that isn't checked out hit a conflict
+    /// at `commit`; it was dropped and the branch left untouched.
+    Abandoned {
+        commit: Oid,
+        paths: Vec<String>,
+    },
+}
+
+/// Rebases `src_branch` onto `onto_branch`, replaying the commits after
+/// `upstream`, or after the branches' merge base when it's `None`.
 pub fn rebase_onto(
     repo: &Repository,
     src_branch: &str,
     onto_branch: &str,
+    upstream: Option<Oid>,
     _non_interactive: bool,
-) -> Result<()> {
+) -> Result<RebaseOutcome> {
     let src = repo.find_branch(src_branch, BranchType::Local)?;
-    let src_refname = src.get().name().context("src refname")?;
-    repo.set_head(src_refname)?;
-    repo.checkout_head(None)?;
+    let src_oid = src.get().target().context("src branch has no target")?;
+    let onto_oid = repo
+        .find_branch(onto_branch, BranchType::Local)?
+        .get()
+        .target()
+        .context("onto branch has no target")?;
+    let base = match upstream {
+        Some(upstream) => upstream,
+        None => fork_point(repo, src_oid, onto_oid)?,
+    };
+    rebase_branch(repo, src_branch, base, onto_oid)
+}
+
+/// File in libgit2's rebase state directory listing the commits to drop;
+/// it goes away with the rest of the state when the rebase ends.
+const DROP_FILE: &str = "git-helper-drop";
+
+/// Replays `upstream..src_branch` onto `onto` and moves the branch there,
+/// dropping commits `onto` already has. The checked-out branch is rebased
+/// in place and may stop on conflicts; any other branch is rebased in
+/// memory, so it never touches the worktree. Once the rebase succeeds, the
+/// old tip is saved under a backup ref before the branch moves.
+pub fn rebase_branch(
+    repo: &Repository,
+    src_branch: &str,
+    upstream: Oid,
+    onto: Oid,
+) -> Result<RebaseOutcome> {
+    let refname = format!("refs/heads/{src_branch}");
+    let tip = repo.refname_to_id(&refname)?;
+    let dropped = already_in_onto(repo, upstream, onto, tip)?;
+    let checked_out = repo.head()?.name() == Some(&refname) && !repo.head_detached()?;
+    if !checked_out && let Some(checkout) = super::refs::checkout_of(repo, &refname)? {
+        let path = checkout.workdir().map(|p| p.display().to_string());
+        anyhow::bail!(
+            "{src_branch} is checked out in {}; rebase it there",
+            path.unwrap_or_default()
+        );
+    }
+    if !checked_out {
+        return rebase_in_memory(repo, &refname, upstream, onto, &dropped);
+    }
 
-    let _onto = repo.revparse_single(&format!("refs/heads/{onto_branch}"))?;
-    let _upstream = repo.revparse_single(&format!("refs/heads/{src_branch}"))?;
+    // Passing the branch as a reference keeps its name so `finish` moves
+    // the branch itself.
+    let src = repo.find_reference(&refname)?;
+    let branch = repo.reference_to_annotated_commit(&src)?;
+    let upstream = repo.find_annotated_commit(upstream)?;
+    let onto = repo.find_annotated_commit(onto)?;
+    let mut rebase = repo
+        .rebase(Some(&branch), Some(&upstream), Some(&onto), None)
+        .with_context(|| format!("start rebase of {src_branch}"))?;
+    if !dropped.is_empty() {
+        let list: String = dropped.iter().map(|oid| format!("{oid}\n")).collect();
+        std::fs::write(repo.path().join("rebase-merge").join(DROP_FILE), list)?;
+    }
+    apply_operations(repo, &mut rebase)
+}
 
+/// Replays `upstream..refname`, minus `dropped`, onto `onto` without a
+/// worktree and moves `refname` to the result, backing up its old tip. A
+/// conflict abandons the whole rebase and leaves no backup.
+pub fn rebase_in_memory(
+    repo: &Repository,
+    refname: &str,
+    upstream: Oid,
+    onto: Oid,
+    dropped: &[Oid],
+) -> Result<RebaseOutcome> {
+    let branch = repo.find_reference(refname)?;
+    let branch = repo.reference_to_annotated_commit(&branch)?;
+    let upstream = repo.find_annotated_commit(upstream)?;
+    let onto_commit = repo.find_annotated_commit(onto)?;
+    let mut opts = RebaseOptions::new();
+    opts.inmemory(true);
     let mut rebase = repo
-        .rebase(None, None, None, None)
-        .context("start base (skeleton)")?;
-    while let Some(_) = rebase.next() {
-        // TODO: apply, handle conflicts; just auto-commit for now
-        rebase.commit(None, &repo.signature()?, None)?;
+        .rebase(
+            Some(&branch),
+            Some(&upstream),
+            Some(&onto_commit),
+            Some(&mut opts),
+        )
+        .with_context(|| format!("start in-memory rebase of {refname}"))?;
+
+    let sig = repo.signature()?;
+    let mut tip = onto;
+    while let Some(op) = rebase.next() {
+        let commit = op.context("apply rebase operation")?.id();
+        if dropped.contains(&commit) {
+            continue;
+        }
+        let index = rebase.inmemory_index()?;
+        if index.has_conflicts() {
+            let paths = conflicted_paths(&index)?;
+            rebase.abort()?;
+            return Ok(RebaseOutcome::Abandoned { commit, paths });
+        }
+        match rebase.commit(None, &sig, None) {
+            Ok(oid) => tip = oid,
+            Err(e) if e.code() == ErrorCode::Applied => {}
+            Err(e) => return Err(e).context("commit rebased patch"),
+        }
+    }
+    rebase.finish(None).context("finish rebase")?;
+
+    // In-memory rebases leave refs alone; move the branch ourselves.
+    if let Some(branch) = refname.strip_prefix("refs/heads/") {
+        super::backup::create(repo, branch, repo.refname_to_id(refname)?)?;
+    }
+    repo.reference(refname, tip, true, "rebase (git-helper): finished")?;
+    Ok(RebaseOutcome::Completed)
+}
+
+/// The commits of `upstream..tip` whose change `onto` already has. Commits
+/// below `upstream` aren't replayed at all, so they are never listed, even
+/// when `onto` has an equivalent (a restacked parent's pre-rewrite commits).
+fn already_in_onto(repo: &Repository, upstream: Oid, onto: Oid, tip: Oid) -> Result<Vec<Oid>> {
+    let replayed = super::merged::local_only(repo, upstream, tip)?;
+    let mut dropped = super::merged::upstream_equivalents(repo, onto, tip)?;
+    dropped.retain(|commit| replayed.contains(commit));
+    Ok(dropped)
+}
+
+/// What `simulate_rebase` expects a rebase to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulatedRebase {
+    /// Where the branch would end up.
+    pub tip: Oid,
+    /// Commits that would stop on conflicts.
+    pub conflicts: Vec<ReplayConflict>,
+    /// Commits dropped because `onto` already has them.
+    pub dropped: Vec<Oid>,
+}
+
+/// A commit that won't apply cleanly when replayed, found by
+/// `simulate_rebase`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayConflict {
+    pub commit: Oid,
+    pub paths: Vec<String>,
+}
+
+/// Replays `upstream..tip` onto `onto` the way `rebase_branch` would, but in
+/// the object database only, leaving refs, the index and the worktree
+/// alone. A conflicted path takes the replayed commit's version so the
+/// commits after it can still be checked.
+pub fn simulate_rebase(
+    repo: &Repository,
+    upstream: Oid,
+    onto: Oid,
+    tip: Oid,
+) -> Result<SimulatedRebase> {
+    let dropped = already_in_onto(repo, upstream, onto, tip)?;
+    let mut walk = repo.revwalk()?;
+    walk.push(tip)?;
+    walk.hide(upstream)?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+
+    let mut current = repo.find_commit(onto)?;
+    let mut conflicts = vec![];
+    for oid in walk {
+        let commit = repo.find_commit(oid?)?;
+        // Like `git rebase`, merge commits are dropped.
+        if commit.parent_count() != 1 || dropped.contains(&commit.id()) {
+            continue;
+        }
+        let ancestor = commit.parent(0)?.tree()?;
+        let ours = current.tree()?;
+        let mut index = repo.merge_trees(&ancestor, &ours, &commit.tree()?, None)?;
+        if index.has_conflicts() {
+            conflicts.push(ReplayConflict {
+                commit: commit.id(),
+                paths: conflicted_paths(&index)?,
+            });
+            take_theirs(&mut index)?;
+        }
+        let tree = index.write_tree_to(repo)?;
+        if tree == ours.id() {
+            continue;
+        }
+        let tree = repo.find_tree(tree)?;
+        let replayed = repo.commit(
+            None,
+            &commit.author(),
+            &commit.committer(),
+            commit.message().unwrap_or_default(),
+            &tree,
+            &[&current],
+        )?;
+        current = repo.find_commit(replayed)?;
+    }
+    Ok(SimulatedRebase {
+        tip: current.id(),
+        conflicts,
+        dropped,
+    })
+}
+
+/// Resolves every conflict in `index` with the incoming side, dropping
+/// paths the incoming side deleted.
+pub(super) fn take_theirs(index: &mut git2::Index) -> Result<()> {
+    let conflicts: Vec<_> = index.conflicts()?.collect::<Result<_, _>>()?;
+    for conflict in conflicts {
+        let entry = conflict
+            .their
+            .as_ref()
+            .or(conflict.our.as_ref())
+            .or(conflict.ancestor.as_ref())
+            .context("conflict without entries")?;
+        let path = std::path::PathBuf::from(String::from_utf8_lossy(&entry.path).into_owned());
+        index.conflict_remove(&path)?;
+        if let Some(mut their) = conflict.their {
+            // Clear the stage bits (0x3000) so it goes back in as a
+            // normal, resolved entry.
+            their.flags &= !0x3000;
+            index.add(&their)?;
+        }
     }
-    let _ = rebase.finish(None);
     Ok(())
 }
+
+/// Commit where `branch` diverged from `onto`; everything after it on
+/// `branch` is unique to the branch and gets replayed by a rebase.
+pub fn fork_point(repo: &Repository, branch: Oid, onto: Oid) -> Result<Oid> {
+    repo.merge_base(branch, onto)
+        .context("branches share no history")
+}
+
+/// Where `tip` forked from the branch whose history `reflog_ref` records
+/// (like `git merge-base --fork-point`): the newest value the ref ever had
+/// that `tip` still contains. Unlike the merge base, this survives the
+/// upstream being rewritten. `None` when no logged value is in `tip`.
+pub fn reflog_fork_point(repo: &Repository, reflog_ref: &str, tip: Oid) -> Result<Option<Oid>> {
+    let reflog = match repo.reflog(reflog_ref) {
+        Ok(reflog) => reflog,
+        Err(e) if e.code() == ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    // Newest first.
+    for entry in reflog.iter() {
+        let oid = entry.id_new();
+        if oid.is_zero() || repo.find_commit(oid).is_err() {
+            continue;
+        }
+        if oid == tip || repo.graph_descendant_of(tip, oid)? {
+            return Ok(Some(oid));
+        }
+    }
+    Ok(None)
+}
+
+pub fn in_progress(repo: &Repository) -> bool {
+    matches!(
+        repo.state(),
+        RepositoryState::Rebase | RepositoryState::RebaseMerge | RepositoryState::RebaseInteractive
+    )
+}
+
+pub fn continue_rebase(repo: &Repository) -> Result<RebaseOutcome> {
+    let mut rebase = repo.open_rebase(None).context("no rebase in progress")?;
+    let index = repo.index()?;
+    if index.has_conflicts() {
+        let commit = rebase
+            .operation_current()
+            .and_then(|i| rebase.nth(i))
+            .map(|op| op.id().to_string())
+            .unwrap_or_default();
+        return Err(GitxError::Conflict {
+            commit,
+            paths: conflicted_paths(&index)?,
+        })
+        .context("unresolved conflicts remain; resolve and stage them first");
+    }
+    if rebase.operation_current().is_some() {
+        commit_current(repo, &mut rebase)?;
+    }
+    apply_operations(repo, &mut rebase)
+}
+
+pub fn skip_rebase(repo: &Repository) -> Result<RebaseOutcome> {
+    let mut rebase = repo.open_rebase(None).context("no rebase in progress")?;
+    discard_changes(repo)?;
+    apply_operations(repo, &mut rebase)
+}
+
+pub fn abort_rebase(repo: &Repository) -> Result<()> {
+    let mut rebase = repo.open_rebase(None).context("no rebase in progress")?;
+    rebase.abort().context("abort rebase")?;
+    Ok(())
+}
+
+fn apply_operations(repo: &Repository, rebase: &mut Rebase<'_>) -> Result<RebaseOutcome> {
+    let dropped = match std::fs::read_to_string(repo.path().join("rebase-merge").join(DROP_FILE)) {
+        Ok(list) => list
+            .lines()
+            .map(Oid::from_str)
+            .collect::<Result<Vec<_>, _>>()?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e).context("read the list of commits to drop"),
+    };
+    while let Some(op) = rebase.next() {
+        let commit = op.context("apply rebase operation")?.id();
+        if dropped.contains(&commit) {
+            discard_changes(repo)?;
+            continue;
+        }
+        let index = repo.index()?;
+        if index.has_conflicts() {
+            return Ok(RebaseOutcome::Conflicted {
+                commit,
+                paths: conflicted_paths(&index)?,
+            });
+        }
+        commit_current(repo, rebase)?;
+    }
+    // `finish` moves the branch, so this is the last point its old tip is
+    // known; a rebase that is aborted instead never gets here.
+    if let (Some(branch), Some(tip)) = (
+        rebase
+            .orig_head_name()
+            .and_then(|name| name.strip_prefix("refs/heads/")),
+        rebase.orig_head_id(),
+    ) {
+        super::backup::create(repo, branch, tip)?;
+    }
+    rebase.finish(None).context("finish rebase")?;
+    Ok(RebaseOutcome::Completed)
+}
+
+/// Throws away whatever the current patch left in the index and worktree.
+/// Unlike a hard reset, this keeps the rebase state on disk.
+fn discard_changes(repo: &Repository) -> Result<()> {
+    let head = repo.head()?.peel_to_tree()?;
+    let mut index = repo.index()?;
+    index.read_tree(&head)?;
+    index.write()?;
+    repo.checkout_tree(head.as_object(), Some(CheckoutBuilder::new().force()))?;
+    Ok(())
+}
+
+fn commit_current(repo: &Repository, rebase: &mut Rebase<'_>) -> Result<()> {
+    let sig = repo.signature()?;
+    match rebase.commit(None, &sig, None) {
+        Ok(_) => Ok(()),
+        // The patch produced no changes, e.g. it was already applied upstream.
+        Err(e) if e.code() == ErrorCode::Applied => Ok(()),
+        Err(e) => Err(e).context("commit rebased patch"),
+    }
+}
+
+pub(super) fn conflicted_paths(index: &git2::Index) -> Result<Vec<String>> {
+    let mut paths = vec![];
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+        let entry = conflict
+            .our
+            .or(conflict.their)
+            .or(conflict.ancestor)
+            .context("conflict without entries")?;
+        paths.push(String::from_utf8_lossy(&entry.path).into_owned());
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitx::test_support::TestRepo;
+
+    /// Starts a rebase of `feature` onto `main` where both branches edited
+    /// the same line of `file.txt`.
+    fn conflicting_rebase(test_repo: &TestRepo) -> Result<RebaseOutcome> {
+        test_repo.commit_file("file.txt", "base\n", "base")?;
+        test_repo.branch("feature")?;
+        test_repo.commit_file("file.txt", "main\n", "main change")?;
+        test_repo.checkout("feature")?;
+        test_repo.commit_file("file.txt", "feature\n", "feature change")?;
+
+        let repo = &test_repo.repo;
+        let main = repo.find_annotated_commit(test_repo.tip("main")?)?;
+        let mut rebase = repo.rebase(None, Some(&main), None, None)?;
+        apply_operations(repo, &mut rebase)
+    }
+
+    #[test]
+    fn test_simulate_rebase_predicts_conflicts() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let base = test_repo.commit_file("file.txt", "base\n", "base")?;
+        test_repo.branch("feature")?;
+        test_repo.commit_file("file.txt", "main\n", "main change")?;
+        test_repo.checkout("feature")?;
+        let clean = test_repo.commit_file("other.txt", "x\n", "feature clean")?;
+        let conflicting = test_repo.commit_file("file.txt", "feature\n", "feature change")?;
+        test_repo.commit_file("file.txt", "feature 2\n", "feature follow-up")?;
+        let before = test_repo.tip("feature")?;
+        let repo = &test_repo.repo;
+
+        let SimulatedRebase {
+            tip,
+            conflicts,
+            dropped,
+        } = simulate_rebase(repo, base, test_repo.tip("main")?, before)?;
+
+        assert!(dropped.is_empty());
+        assert_eq!(
+            conflicts,
+            vec![ReplayConflict {
+                commit: conflicting,
+                paths: vec!["file.txt".to_string()],
+            }]
+        );
+        let tip = repo.find_commit(tip)?;
+        assert_eq!(tip.summary(), Some("feature follow-up"));
+        assert_eq!(tip.parent(0)?.parent(0)?.summary(), Some("feature clean"));
+        assert_ne!(tip.parent(0)?.parent(0)?.id(), clean);
+        // Nothing real moved.
+        assert_eq!(test_repo.tip("feature")?, before);
+        assert!(!in_progress(repo));
+        assert!(!repo.index()?.has_conflicts());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebase_drops_commits_already_upstream() -> Result<()> {
+        for checked_out in [true, false] {
+            let test_repo = TestRepo::new()?;
+            test_repo.commit_file("file.txt", "base\n", "base")?;
+            test_repo.branch("feature")?;
+            test_repo.checkout("feature")?;
+            test_repo.commit_file("file.txt", "picked\n", "picked")?;
+            test_repo.commit_file("b.txt", "b\n", "feature b")?;
+            test_repo.checkout("main")?;
+            // Cherry-picked, then built on, so replaying it would conflict.
+            test_repo.commit_file("file.txt", "picked\n", "picked (cherry-picked)")?;
+            test_repo.commit_file("file.txt", "later\n", "later main work")?;
+            if checked_out {
+                test_repo.checkout("feature")?;
+            }
+
+            let outcome = rebase_onto(&test_repo.repo, "feature", "main", None, true)?;
+
+            assert_eq!(outcome, RebaseOutcome::Completed);
+            let tip = test_repo.repo.find_commit(test_repo.tip("feature")?)?;
+            assert_eq!(tip.summary(), Some("feature b"));
+            assert_eq!(tip.parent_id(0)?, test_repo.tip("main")?);
+            assert!(!in_progress(&test_repo.repo));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_reflog_fork_point_survives_rewritten_upstream() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let repo = &test_repo.repo;
+        let remote_ref = "refs/remotes/origin/main";
+        let base = test_repo.commit_file("base.txt", "base\n", "base")?;
+        repo.reference(remote_ref, base, true, "fetch")?;
+        let old = test_repo.commit_file("old.txt", "old\n", "old upstream")?;
+        repo.reference(remote_ref, old, true, "fetch")?;
+        test_repo.branch("feature")?;
+        test_repo.checkout("feature")?;
+        let feature = test_repo.commit_file("a.txt", "a\n", "feature a")?;
+        // Upstream drops "old upstream" and force-pushes new work on base.
+        repo.reference("refs/heads/main", base, true, "reset")?;
+        test_repo.checkout("main")?;
+        let new = test_repo.commit_file("new.txt", "new\n", "new upstream")?;
+        repo.reference(remote_ref, new, true, "fetch: forced-update")?;
+
+        assert_eq!(fork_point(repo, feature, new)?, base);
+        assert_eq!(reflog_fork_point(repo, remote_ref, feature)?, Some(old));
+        assert_eq!(reflog_fork_point(repo, "refs/remotes/none", feature)?, None);
+
+        let outcome = rebase_onto(repo, "feature", "main", Some(old), true)?;
+
+        assert_eq!(outcome, RebaseOutcome::Completed);
+        let tip = repo.find_commit(test_repo.tip("feature")?)?;
+        assert_eq!(tip.summary(), Some("feature a"));
+        assert_eq!(tip.parent_id(0)?, new);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebase_onto_replays_branch_commits() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let base = test_repo.commit_file("base.txt", "base\n", "base")?;
+        test_repo.branch("feature")?;
+        test_repo.commit_file("main.txt", "main\n", "main change")?;
+        test_repo.checkout("feature")?;
+        test_repo.commit_file("a.txt", "a\n", "feature a")?;
+        test_repo.commit_file("b.txt", "b\n", "feature b")?;
+        // Rebase from a different checkout to make sure HEAD isn't assumed.
+        test_repo.checkout("main")?;
+        let fork = fork_point(
+            &test_repo.repo,
+            test_repo.tip("feature")?,
+            test_repo.tip("main")?,
+        )?;
+        assert_eq!(fork, base);
+
+        let outcome = rebase_onto(&test_repo.repo, "feature", "main", None, true)?;
+
+        assert_eq!(outcome, RebaseOutcome::Completed);
+        let tip = test_repo.repo.find_commit(test_repo.tip("feature")?)?;
+        assert_eq!(tip.summary(), Some("feature b"));
+        let parent = tip.parent(0)?;
+        assert_eq!(parent.summary(), Some("feature a"));
+        assert_eq!(parent.parent_id(0)?, test_repo.tip("main")?);
+        // feature wasn't checked out, so it was rebased without touching HEAD.
+        assert_eq!(test_repo.repo.head()?.shorthand(), Some("main"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_rebase_abandons_on_conflict() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("file.txt", "base\n", "base")?;
+        test_repo.branch("feature")?;
+        test_repo.commit_file("file.txt", "main\n", "main change")?;
+        test_repo.checkout("feature")?;
+        test_repo.commit_file("file.txt", "feature\n", "feature change")?;
+        test_repo.checkout("main")?;
+        let before = test_repo.tip("feature")?;
+
+        let outcome = rebase_onto(&test_repo.repo, "feature", "main", None, true)?;
+
+        match outcome {
+            RebaseOutcome::Abandoned { paths, .. } => assert_eq!(paths, vec!["file.txt"]),
+            other => panic!("expected abandoned rebase, got {other:?}"),
+        }
+        assert_eq!(test_repo.tip("feature")?, before);
+        assert!(!in_progress(&test_repo.repo));
+        assert!(super::super::backup::list(&test_repo.repo)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebase_stops_on_conflict() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let outcome = conflicting_rebase(&test_repo)?;
+
+        match outcome {
+            RebaseOutcome::Conflicted { paths, .. } => assert_eq!(paths, vec!["file.txt"]),
+            other => panic!("expected conflict, got {other:?}"),
+        }
+        assert!(in_progress(&test_repo.repo));
+        Ok(())
+    }
+
+    #[test]
+    fn test_continue_after_resolving() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        conflicting_rebase(&test_repo)?;
+        let old = test_repo.tip("feature")?;
+
+        assert!(continue_rebase(&test_repo.repo).is_err());
+        assert!(super::super::backup::list(&test_repo.repo)?.is_empty());
+
+        test_repo.stage_file("file.txt", "resolved\n")?;
+        assert_eq!(continue_rebase(&test_repo.repo)?, RebaseOutcome::Completed);
+        assert!(!in_progress(&test_repo.repo));
+
+        let head = test_repo.repo.head()?.peel_to_commit()?;
+        assert_eq!(head.summary(), Some("feature change"));
+        assert_eq!(head.parent_id(0)?, test_repo.tip("main")?);
+        let backups = super::super::backup::list(&test_repo.repo)?;
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].tip, old);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restack_drops_nothing_below_the_old_parent() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let base = test_repo.commit_file("base.txt", "base\n", "base")?;
+        test_repo.branch("parent")?;
+        test_repo.checkout("parent")?;
+        let old_parent = test_repo.commit_file("parent.txt", "parent\n", "parent work")?;
+        test_repo.branch("child")?;
+        test_repo.checkout("child")?;
+        test_repo.commit_file("child.txt", "child\n", "child work")?;
+        // The parent is rewritten: same change, new commit.
+        let repo = &test_repo.repo;
+        repo.reference("refs/heads/parent", base, true, "reset")?;
+        test_repo.checkout("parent")?;
+        let new_parent =
+            test_repo.commit_file("parent.txt", "parent\n", "parent work, reworded")?;
+        test_repo.checkout("main")?;
+        let tip = test_repo.tip("child")?;
+
+        let simulated = simulate_rebase(repo, old_parent, new_parent, tip)?;
+        assert_eq!(simulated.dropped, vec![]);
+        assert!(simulated.conflicts.is_empty());
+
+        let outcome = rebase_branch(repo, "child", old_parent, new_parent)?;
+
+        assert_eq!(outcome, RebaseOutcome::Completed);
+        let tip = repo.find_commit(test_repo.tip("child")?)?;
+        assert_eq!(tip.summary(), Some("child work"));
+        assert_eq!(tip.parent_id(0)?, new_parent);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebase_branch_backs_up_old_tip() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let base = test_repo.commit_file("base.txt", "base\n", "base")?;
+        test_repo.branch("feature")?;
+        let main = test_repo.commit_file("main.txt", "main\n", "main change")?;
+        test_repo.checkout("feature")?;
+        let old = test_repo.commit_file("a.txt", "a\n", "feature a")?;
+        let repo = &test_repo.repo;
+
+        assert_eq!(
+            rebase_branch(repo, "feature", base, main)?,
+            RebaseOutcome::Completed
+        );
+
+        let backups = super::super::backup::list(repo)?;
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].branch, "feature");
+        assert_eq!(backups[0].tip, old);
+        assert_ne!(test_repo.tip("feature")?, old);
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_drops_conflicting_commit() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("file.txt", "base\n", "base")?;
+        test_repo.branch("feature")?;
+        let main = test_repo.commit_file("file.txt", "main\n", "main change")?;
+        test_repo.checkout("feature")?;
+        test_repo.commit_file("file.txt", "feature\n", "feature change")?;
+        test_repo.commit_file("later.txt", "later\n", "feature later")?;
+        let repo = &test_repo.repo;
+        let onto = repo.find_annotated_commit(main)?;
+        let mut rebase = repo.rebase(None, Some(&onto), None, None)?;
+        assert!(matches!(
+            apply_operations(repo, &mut rebase)?,
+            RebaseOutcome::Conflicted { .. }
+        ));
+
+        // Skipping must keep the rebase going, not end it at the stop.
+        assert_eq!(skip_rebase(repo)?, RebaseOutcome::Completed);
+        assert!(!in_progress(repo));
+        let tip = repo.find_commit(test_repo.tip("feature")?)?;
+        assert_eq!(tip.summary(), Some("feature later"));
+        assert_eq!(tip.parent_id(0)?, main);
+        assert_eq!(
+            std::fs::read_to_string(test_repo.path().join("file.txt"))?,
+            "main\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_abort_restores_branch() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        conflicting_rebase(&test_repo)?;
+        let before = test_repo.tip("feature")?;
+
+        abort_rebase(&test_repo.repo)?;
+
+        assert!(!in_progress(&test_repo.repo));
+        assert_eq!(test_repo.tip("feature")?, before);
+        assert_eq!(test_repo.repo.head()?.shorthand(), Some("feature"));
+        assert!(super::super::backup::list(&test_repo.repo)?.is_empty());
+        Ok(())
+    }
+}
@@ -1,22 +1,473 @@
+use super::GitxError;
 use anyhow::{Context, Result};
-use git2::{BranchType, Repository};
+use git2::build::CheckoutBuilder;
+use git2::{BranchType, ErrorCode, Oid, Repository};
+use std::collections::BTreeMap;
 
-pub fn fast_forward_to_upstream(repo: &Repository, branch: &str) -> Result<()> {
-    let local = repo.find_branch(branch, BranchType::Local)?;
-    let upstream = local.upstream().context("no upstream for branch")?;
-    let upstream_oid = upstream.get().target().context("upstream no target")?;
-    let annotated = repo.find_annotated_commit(upstream_oid)?;
+/// How a local branch relates to its remote-tracking counterpart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchStatus {
+    UpToDate,
+    /// Local is strictly behind; a fast-forward will catch it up.
+    Behind,
+    /// Local has commits the remote lacks, and nothing new from the remote.
+    Ahead,
+    Diverged {
+        ahead: usize,
+        behind: usize,
+    },
+    /// The remote-tracking branch exists but the local branch doesn't yet.
+    MissingLocal,
+    /// There is no `<remote>/<branch>` to sync from.
+    MissingRemote,
+}
+
+pub fn branch_status(repo: &Repository, branch: &str, remote: &str) -> Result<BranchStatus> {
+    let Some(target) = ref_target(repo, &format!("refs/remotes/{remote}/{branch}"))? else {
+        return Ok(BranchStatus::MissingRemote);
+    };
+    let Some(local) = ref_target(repo, &format!("refs/heads/{branch}"))? else {
+        return Ok(BranchStatus::MissingLocal);
+    };
+    Ok(match repo.graph_ahead_behind(local, target)? {
+        (0, 0) => BranchStatus::UpToDate,
+        (0, _) => BranchStatus::Behind,
+        (_, 0) => BranchStatus::Ahead,
+        (ahead, behind) => BranchStatus::Diverged { ahead, behind },
+    })
+}
+
+/// Moves `branch` to `<remote>/<branch>`, creating it if needed. Refuses
+/// anything but a fast-forward, and anything but `expected` (the remote tip
+/// the caller planned with), and keeps the worktree in step when the
+/// branch is checked out here or in another worktree.
+pub fn fast_forward_branch(
+    repo: &Repository,
+    branch: &str,
+    remote: &str,
+    expected: Oid,
+) -> Result<()> {
+    let refname = format!("refs/heads/{branch}");
+    let target =
+        ref_target(repo, &format!("refs/remotes/{remote}/{branch}"))?.ok_or_else(|| {
+            GitxError::MissingUpstream {
+                remote: remote.to_string(),
+                branch: branch.to_string(),
+            }
+        })?;
+    if target != expected {
+        anyhow::bail!(
+            "{remote}/{branch} moved to {target} since the fast-forward to {expected} \
+             was planned; plan again to review the new tip"
+        );
+    }
+
+    match branch_status(repo, branch, remote)? {
+        BranchStatus::UpToDate | BranchStatus::Ahead => return Ok(()),
+        BranchStatus::Diverged { ahead, behind } => {
+            return Err(GitxError::Diverged {
+                remote: remote.to_string(),
+                branch: branch.to_string(),
+                ahead,
+                behind,
+            }
+            .into());
+        }
+        BranchStatus::MissingLocal => {
+            repo.reference(
+                &refname,
+                target,
+                false,
+                &format!("branch: created from {remote}/{branch}"),
+            )?;
+            return Ok(());
+        }
+        BranchStatus::Behind | BranchStatus::MissingRemote => {}
+    }
+
+    // Update the files before the ref, so a safe checkout can still tell
+    // local edits apart from what the old tip had.
+    if let Some(checkout) = checkout_of(repo, &refname)? {
+        if super::stash::is_dirty(&checkout)? {
+            let path = checkout.workdir().map(|p| p.display().to_string());
+            return Err(GitxError::DirtyWorktree {
+                path: path.unwrap_or_default(),
+            })
+            .with_context(|| format!("{branch} is checked out with uncommitted changes"));
+        }
+        checkout.checkout_tree(&checkout.find_object(target, None)?, None)?;
+    }
+    let mut reference = repo.find_reference(&refname)?;
+    reference.set_target(
+        target,
+        &format!("fast-forward {branch} to {remote}/{branch}"),
+    )?;
+    Ok(())
+}
+
+/// Points `branch` at `<remote>/<branch>` whatever their history, for a
+/// branch whose remote was rewritten. `branch` must still be at `expected`;
+/// that tip is saved under a backup ref first, whose name is returned. A
+/// checkout of the branch is updated too, but only if it is clean.
+pub fn reset_to_upstream(
+    repo: &Repository,
+    branch: &str,
+    remote: &str,
+    expected: Oid,
+) -> Result<String> {
+    let refname = format!("refs/heads/{branch}");
+    let target =
+        ref_target(repo, &format!("refs/remotes/{remote}/{branch}"))?.ok_or_else(|| {
+            GitxError::MissingUpstream {
+                remote: remote.to_string(),
+                branch: branch.to_string(),
+            }
+        })?;
+    let tip = ref_target(repo, &refname)?.with_context(|| format!("no branch {branch}"))?;
+    if tip != expected {
+        anyhow::bail!("{branch} moved to {tip} since it was planned for reset at {expected}");
+    }
+
+    check_movable(repo, branch)?;
+    let backup = super::backup::create(repo, branch, tip)?;
+    move_branch(
+        repo,
+        branch,
+        target,
+        &format!("reset {branch} to {remote}/{branch} (was {tip}, see {backup})"),
+    )?;
+    Ok(backup)
+}
+
+/// Fails if `branch` is checked out somewhere with uncommitted changes,
+/// which moving it would have to overwrite.
+pub fn check_movable(repo: &Repository, branch: &str) -> Result<()> {
+    if let Some(checkout) = checkout_of(repo, &format!("refs/heads/{branch}"))?
+        && super::stash::is_dirty(&checkout)?
+    {
+        let path = checkout.workdir().map(|p| p.display().to_string());
+        return Err(GitxError::DirtyWorktree {
+            path: path.unwrap_or_default(),
+        })
+        .with_context(|| format!("{branch} is checked out with uncommitted changes"));
+    }
+    Ok(())
+}
 
-    let (analysis, _pref) = repo.merge_analysis(&[&annotated])?;
-    if analysis.is_fast_forward() {
-        let name = local.get().name().unwrap_or("");
-        let target = upstream_oid;
-        let mut reference = repo.find_reference(name)?;
-        reference.set_target(target, &format!("fast-forward {name}"))?;
+/// Points `branch` at `target`, creating it if needed, whatever their
+/// history. A checkout of the branch is updated too; call `check_movable`
+/// first.
+pub fn move_branch(repo: &Repository, branch: &str, target: Oid, log: &str) -> Result<()> {
+    let refname = format!("refs/heads/{branch}");
+    if let Some(checkout) = checkout_of(repo, &refname)? {
+        checkout.checkout_tree(&checkout.find_object(target, None)?, None)?;
+    }
+    repo.reference(&refname, target, true, log)?;
+    Ok(())
+}
+
+/// The worktree (this one, the main one or a linked one) that has `refname`
+/// checked out, if any.
+pub fn checkout_of(repo: &Repository, refname: &str) -> Result<Option<Repository>> {
+    let mut candidates = vec![Repository::open(repo.path())?];
+    if repo.is_worktree() {
+        candidates.push(Repository::open(repo.commondir())?);
+    }
+    for name in repo.worktrees()?.iter().flatten() {
+        let worktree = repo.find_worktree(name)?;
+        if worktree.validate().is_ok() {
+            candidates.push(Repository::open_from_worktree(&worktree)?);
+        }
+    }
+    for candidate in candidates {
+        if candidate.is_bare() || candidate.head_detached().unwrap_or(true) {
+            continue;
+        }
+        let on_branch = candidate
+            .find_reference("HEAD")?
+            .symbolic_target()
+            .is_some_and(|target| target == refname);
+        if on_branch {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
 
-        if repo.head()?.name() == reference.name() {
-            repo.checkout_head(None)?;
+pub fn ref_target(repo: &Repository, name: &str) -> Result<Option<Oid>> {
+    match repo.find_reference(name) {
+        Ok(reference) => Ok(reference.target()),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Local branches whose `branch.<name>.remote` is `remote` and that track
+/// the same-named branch there, in name order.
+pub fn tracked_branches(repo: &Repository, remote: &str) -> Result<Vec<String>> {
+    let config = repo.config()?.snapshot()?;
+    let mut branches = vec![];
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        let Some(name) = branch.name()? else { continue };
+        let tracks_remote = config
+            .get_string(&format!("branch.{name}.remote"))
+            .is_ok_and(|r| r == remote);
+        let merge = config.get_string(&format!("branch.{name}.merge")).ok();
+        if tracks_remote && merge.as_deref() == Some(&format!("refs/heads/{name}")) {
+            branches.push(name.to_string());
         }
     }
+    branches.sort();
+    Ok(branches)
+}
+
+/// Deletes `branch`, but only if it still points at `expected`, keeping its
+/// tip under a backup ref.
+pub fn delete_branch(repo: &Repository, branch: &str, expected: Oid) -> Result<()> {
+    let mut local = repo.find_branch(branch, BranchType::Local)?;
+    let tip = local.get().target().context("branch has no target")?;
+    if tip != expected {
+        anyhow::bail!("{branch} moved to {tip} since it was planned for deletion at {expected}");
+    }
+    super::backup::create(repo, branch, tip)?;
+    local
+        .delete()
+        .with_context(|| format!("delete branch {branch}"))?;
     Ok(())
 }
+
+/// Tips of every local branch, keyed by full ref name.
+pub fn local_branch_tips(repo: &Repository) -> Result<BTreeMap<String, Oid>> {
+    let mut tips = BTreeMap::new();
+    for reference in repo.references_glob("refs/heads/*")? {
+        let reference = reference?;
+        if let (Some(name), Some(oid)) = (reference.name(), reference.target()) {
+            tips.insert(name.to_string(), oid);
+        }
+    }
+    Ok(tips)
+}
+
+/// What HEAD points at: a ref name when on a branch, otherwise the commit id.
+pub fn head_state(repo: &Repository) -> Result<String> {
+    if repo.head_detached()? {
+        let oid = repo.head()?.target().context("HEAD has no target")?;
+        return Ok(oid.to_string());
+    }
+    let head = repo.find_reference("HEAD")?;
+    let target = head.symbolic_target().context("HEAD not symbolic")?;
+    Ok(target.to_string())
+}
+
+/// Point `name` at `oid`, or delete it when `oid` is `None`.
+pub fn restore_ref(repo: &Repository, name: &str, oid: Option<Oid>, log: &str) -> Result<()> {
+    match oid {
+        Some(oid) => {
+            repo.reference(name, oid, true, log)?;
+        }
+        None => {
+            if let Ok(mut reference) = repo.find_reference(name) {
+                reference.delete()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Re-attach HEAD as recorded by [`head_state`] and force the worktree to match.
+pub fn restore_head(repo: &Repository, state: &str) -> Result<()> {
+    if state.starts_with("refs/") {
+        repo.set_head(state)?;
+    } else {
+        repo.set_head_detached(Oid::from_str(state)?)?;
+    }
+    repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitx::test_support::TestRepo;
+
+    fn set_remote_main(test_repo: &TestRepo, oid: Oid) -> Result<()> {
+        test_repo
+            .repo
+            .reference("refs/remotes/origin/main", oid, true, "test")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_tracked_branches() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("file.txt", "one\n", "one")?;
+        test_repo.branch("feature")?;
+        test_repo.branch("local-only")?;
+        test_repo.branch("renamed")?;
+        let mut config = test_repo.repo.config()?;
+        for name in ["main", "feature", "renamed"] {
+            config.set_str(&format!("branch.{name}.remote"), "origin")?;
+            config.set_str(
+                &format!("branch.{name}.merge"),
+                &format!("refs/heads/{name}"),
+            )?;
+        }
+        config.set_str("branch.renamed.merge", "refs/heads/other")?;
+
+        assert_eq!(
+            tracked_branches(&test_repo.repo, "origin")?,
+            vec!["feature", "main"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fast_forward_without_upstream_config() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let base = test_repo.commit_file("file.txt", "one\n", "one")?;
+        test_repo.branch("feature")?;
+        let ahead = test_repo.commit_file("file.txt", "two\n", "two")?;
+        set_remote_main(&test_repo, ahead)?;
+        test_repo
+            .repo
+            .reference("refs/heads/main", base, true, "rewind")?;
+        test_repo.checkout("feature")?;
+
+        assert_eq!(
+            branch_status(&test_repo.repo, "main", "origin")?,
+            BranchStatus::Behind
+        );
+        assert!(fast_forward_branch(&test_repo.repo, "main", "origin", base).is_err());
+        assert_eq!(test_repo.tip("main")?, base);
+        fast_forward_branch(&test_repo.repo, "main", "origin", ahead)?;
+        assert_eq!(test_repo.tip("main")?, ahead);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fast_forward_refuses_diverged() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("file.txt", "one\n", "one")?;
+        test_repo.branch("other")?;
+        test_repo.commit_file("file.txt", "local\n", "local")?;
+        test_repo.checkout("other")?;
+        let remote = test_repo.commit_file("file.txt", "remote\n", "remote")?;
+        set_remote_main(&test_repo, remote)?;
+
+        assert_eq!(
+            branch_status(&test_repo.repo, "main", "origin")?,
+            BranchStatus::Diverged {
+                ahead: 1,
+                behind: 1
+            }
+        );
+        let err = fast_forward_branch(&test_repo.repo, "main", "origin", remote).unwrap_err();
+        assert!(err.to_string().contains("1 ahead, 1 behind"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_to_upstream_backs_up_old_tip() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("file.txt", "one\n", "one")?;
+        test_repo.branch("other")?;
+        let local = test_repo.commit_file("local.txt", "local\n", "local")?;
+        test_repo.checkout("other")?;
+        let remote = test_repo.commit_file("file.txt", "remote\n", "remote")?;
+        set_remote_main(&test_repo, remote)?;
+        test_repo.checkout("main")?;
+
+        assert!(reset_to_upstream(&test_repo.repo, "main", "origin", remote).is_err());
+        assert_eq!(test_repo.tip("main")?, local);
+
+        std::fs::write(test_repo.path().join("file.txt"), "edited\n")?;
+        assert!(reset_to_upstream(&test_repo.repo, "main", "origin", local).is_err());
+        assert_eq!(test_repo.tip("main")?, local);
+        std::fs::write(test_repo.path().join("file.txt"), "one\n")?;
+
+        let backup = reset_to_upstream(&test_repo.repo, "main", "origin", local)?;
+        assert!(backup.starts_with("refs/git-helper/backup/") && backup.ends_with("/main"));
+        assert_eq!(ref_target(&test_repo.repo, &backup)?, Some(local));
+        assert_eq!(test_repo.tip("main")?, remote);
+        assert!(!test_repo.path().join("local.txt").exists());
+        assert_eq!(
+            std::fs::read_to_string(test_repo.path().join("file.txt"))?,
+            "remote\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_branch_backs_up_tip() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let base = test_repo.commit_file("file.txt", "one\n", "one")?;
+        test_repo.branch("done")?;
+        test_repo.checkout("done")?;
+        let tip = test_repo.commit_file("done.txt", "done\n", "done")?;
+        test_repo.checkout("main")?;
+        let repo = &test_repo.repo;
+
+        assert!(delete_branch(repo, "done", base).is_err());
+        assert!(super::super::backup::list(repo)?.is_empty());
+
+        delete_branch(repo, "done", tip)?;
+        assert!(repo.find_branch("done", BranchType::Local).is_err());
+        let backups = super::super::backup::list(repo)?;
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].branch, "done");
+        assert_eq!(backups[0].tip, tip);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fast_forward_creates_missing_branch() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let tip = test_repo.commit_file("file.txt", "one\n", "one")?;
+        test_repo
+            .repo
+            .reference("refs/remotes/origin/develop", tip, true, "test")?;
+
+        assert_eq!(
+            branch_status(&test_repo.repo, "develop", "origin")?,
+            BranchStatus::MissingLocal
+        );
+        fast_forward_branch(&test_repo.repo, "develop", "origin", tip)?;
+        assert_eq!(test_repo.tip("develop")?, tip);
+        assert_eq!(
+            branch_status(&test_repo.repo, "nope", "origin")?,
+            BranchStatus::MissingRemote
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fast_forward_updates_other_worktree() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let base = test_repo.commit_file("file.txt", "one\n", "one")?;
+        let ahead = test_repo.commit_file("file.txt", "two\n", "two")?;
+        set_remote_main(&test_repo, ahead)?;
+        test_repo
+            .repo
+            .reference("refs/heads/main", base, true, "rewind")?;
+        test_repo.branch("feature")?;
+        test_repo.checkout("feature")?;
+
+        // Check main out in a linked worktree.
+        let wt_dir = tempfile::tempdir()?;
+        let wt_path = wt_dir.path().join("wt");
+        let main_ref = test_repo.repo.find_reference("refs/heads/main")?;
+        test_repo.repo.worktree(
+            "wt",
+            &wt_path,
+            Some(git2::WorktreeAddOptions::new().reference(Some(&main_ref))),
+        )?;
+        assert_eq!(std::fs::read_to_string(wt_path.join("file.txt"))?, "one\n");
+
+        fast_forward_branch(&test_repo.repo, "main", "origin", ahead)?;
+
+        assert_eq!(test_repo.tip("main")?, ahead);
+        assert_eq!(std::fs::read_to_string(wt_path.join("file.txt"))?, "two\n");
+        Ok(())
+    }
+}
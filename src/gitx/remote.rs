@@ -1,51 +1,653 @@
+use super::GitxError;
 use anyhow::{Context, Result};
-use git2::Repository;
+use git2::{
+    Cred, CredentialType, ErrorClass, ErrorCode, FetchOptions, FetchPrune, Oid, PushOptions,
+    RemoteCallbacks, Repository,
+};
+use indicatif::{HumanBytes, ProgressBar};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
 use tracing::info;
 
-pub fn fetch_prune(repo: &Repository, remote_name: &str) -> Result<()> {
-    // Use git command directly to properly support SSH config
-    let workdir = repo.workdir().context("no workdir")?;
+/// Environment variable holding a token for HTTPS remotes (libgit2 backend).
+pub const TOKEN_ENV: &str = "GIT_HELPER_TOKEN";
 
-    let output = std::process::Command::new("git")
-        .arg("fetch")
-        .arg("--prune")
-        .arg(remote_name)
-        .current_dir(workdir)
-        .output()
-        .context("failed to execute git fetch")?;
+/// How fetches and pushes reach the remote, chosen by `[transport] backend`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportBackend {
+    /// Shell out to the `git` binary, honouring the user's SSH config.
+    #[default]
+    Cli,
+    /// Talk to the remote through libgit2, without needing `git` on PATH.
+    Libgit2,
+}
 
-    if !&output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("git fetch failed: {}", stderr);
+impl TransportBackend {
+    pub fn transport(self) -> Box<dyn Transport> {
+        match self {
+            TransportBackend::Cli => Box::new(CliTransport),
+            TransportBackend::Libgit2 => Box::new(Libgit2Transport),
+        }
     }
+}
 
-    let stderr = String::from_utf8_lossy(&output.stderr);
-    if !stderr.is_empty() {
-        info!("fetched and pruned remote:\n{}", stderr);
-    } else {
-        info!("fetched and pruned remote: {}", remote_name);
+/// Fetch and push, reporting transfer progress on the running op's line.
+pub trait Transport {
+    fn fetch_prune(
+        &self,
+        repo: &Repository,
+        remote_name: &str,
+        progress: &ProgressBar,
+    ) -> Result<()>;
+
+    fn push_ff_only(
+        &self,
+        repo: &Repository,
+        remote_name: &str,
+        branch: &str,
+        progress: &ProgressBar,
+    ) -> Result<()>;
+
+    /// Force-pushes `branch`, but only if the remote still has it at
+    /// `expected` (`None`: only if the remote doesn't have it at all).
+    fn push_force_with_lease(
+        &self,
+        repo: &Repository,
+        remote_name: &str,
+        branch: &str,
+        expected: Option<Oid>,
+        progress: &ProgressBar,
+    ) -> Result<()>;
+}
+
+pub struct CliTransport;
+
+impl Transport for CliTransport {
+    fn fetch_prune(
+        &self,
+        repo: &Repository,
+        remote_name: &str,
+        progress: &ProgressBar,
+    ) -> Result<()> {
+        // Use git command directly to properly support SSH config
+        let (success, stderr) = run_git(repo, &["fetch", "--prune", remote_name], progress)?;
+
+        if !success {
+            return Err(classify_cli_failure(&stderr, remote_name, None)
+                .unwrap_or_else(|| anyhow::anyhow!("git fetch failed: {}", stderr)));
+        }
+
+        if !stderr.is_empty() {
+            info!("fetched and pruned remote:\n{}", stderr);
+        } else {
+            info!("fetched and pruned remote: {}", remote_name);
+        }
+
+        Ok(())
     }
 
-    Ok(())
+    fn push_ff_only(
+        &self,
+        repo: &Repository,
+        remote_name: &str,
+        branch: &str,
+        progress: &ProgressBar,
+    ) -> Result<()> {
+        // Use git command directly to properly support SSH config
+        let refspec = format!("refs/heads/{branch}:refs/heads/{branch}");
+        let (success, stderr) = run_git(repo, &["push", remote_name, &refspec], progress)?;
+
+        if !success {
+            return Err(classify_cli_failure(&stderr, remote_name, Some(branch))
+                .unwrap_or_else(|| anyhow::anyhow!("git push failed: {}", stderr)));
+        }
+
+        Ok(())
+    }
+
+    fn push_force_with_lease(
+        &self,
+        repo: &Repository,
+        remote_name: &str,
+        branch: &str,
+        expected: Option<Oid>,
+        progress: &ProgressBar,
+    ) -> Result<()> {
+        // An empty expected value tells git the ref must not exist on the remote.
+        let expected = expected.map(|oid| oid.to_string()).unwrap_or_default();
+        let lease = format!("--force-with-lease=refs/heads/{branch}:{expected}");
+        let refspec = format!("refs/heads/{branch}:refs/heads/{branch}");
+        let (success, stderr) = run_git(repo, &["push", &lease, remote_name, &refspec], progress)?;
+
+        if !success {
+            return Err(
+                classify_cli_failure(&stderr, remote_name, Some(branch)).unwrap_or_else(|| {
+                    anyhow::anyhow!("git push --force-with-lease failed: {}", stderr)
+                }),
+            );
+        }
+
+        Ok(())
+    }
 }
 
-pub fn push_ff_only(repo: &Repository, remote_name: &str, branch: &str) -> Result<()> {
-    // Use git command directly to properly support SSH config
+/// Runs `git <args> --progress` in the worktree, showing the progress git
+/// reports on `progress`. Returns whether it succeeded and the rest of
+/// what it printed to stderr.
+fn run_git(repo: &Repository, args: &[&str], progress: &ProgressBar) -> Result<(bool, String)> {
     let workdir = repo.workdir().context("no workdir")?;
-
-    let refspec = format!("refs/heads/{branch}:refs/heads/{branch}");
-    let output = std::process::Command::new("git")
-        .arg("push")
-        .arg(remote_name)
-        .arg(&refspec)
+    let mut child = Command::new("git")
+        .args(&args[..1])
+        .arg("--progress")
+        .args(&args[1..])
         .current_dir(workdir)
-        .output()
-        .context("failed to execute git push")?;
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to execute git {}", args[0]))?;
+    let stderr = child.stderr.take().context("git stderr not captured")?;
+    let stderr = read_progress(BufReader::new(stderr), progress);
+    let status = child.wait()?;
+    Ok((status.success(), stderr?))
+}
+
+/// Splits git's stderr into progress updates, shown on `progress`, and
+/// everything else, which is returned. Git redraws a progress line by
+/// ending it with `\r`, and ends it with `, done.` and a newline.
+fn read_progress(stderr: impl BufRead, progress: &ProgressBar) -> Result<String> {
+    let label = progress.message();
+    let mut rest = String::new();
+    for line in stderr.split(b'\n') {
+        let line = line?;
+        let mut updates = line.split(|&byte| byte == b'\r').peekable();
+        while let Some(update) = updates.next() {
+            let text = String::from_utf8_lossy(update);
+            let text = text.trim_end();
+            if text.is_empty() {
+                continue;
+            }
+            if updates.peek().is_some() || is_progress(text) {
+                progress.set_message(format!("{label} — {}", text.trim_start()));
+            } else {
+                rest.push_str(text);
+                rest.push('\n');
+            }
+        }
+    }
+    Ok(rest)
+}
+
+/// Whether `line` is one of git's progress lines, e.g. `Receiving objects:
+/// 100% (3/3), done.` or `remote: Counting objects: 5, done.`.
+fn is_progress(line: &str) -> bool {
+    let line = line.strip_prefix("remote: ").unwrap_or(line);
+    let Some((title, count)) = line.split_once(": ") else {
+        return false;
+    };
+    title.starts_with(|c: char| c.is_ascii_uppercase())
+        && title.chars().all(|c| c.is_ascii_alphabetic() || c == ' ')
+        && count.trim_start().starts_with(|c: char| c.is_ascii_digit())
+}
+
+pub struct Libgit2Transport;
+
+impl Transport for Libgit2Transport {
+    fn fetch_prune(
+        &self,
+        repo: &Repository,
+        remote_name: &str,
+        progress: &ProgressBar,
+    ) -> Result<()> {
+        let config = repo.config()?;
+        let mut remote = repo.find_remote(remote_name)?;
+        let label = progress.message();
+        let mut callbacks = callbacks(&config);
+        callbacks.transfer_progress(|stats| {
+            progress.set_message(format!("{label} — {}", describe_fetch(&stats)));
+            true
+        });
+        let mut options = FetchOptions::new();
+        options.remote_callbacks(callbacks).prune(FetchPrune::On);
+        remote
+            .fetch(&[] as &[&str], Some(&mut options), None)
+            .map_err(|err| classify_git2_failure(err, remote_name, None))
+            .with_context(|| format!("fetch {remote_name}"))?;
+
+        let stats = remote.stats();
+        info!(
+            "fetched and pruned remote: {remote_name} ({} objects)",
+            stats.received_objects()
+        );
+        Ok(())
+    }
+
+    fn push_ff_only(
+        &self,
+        repo: &Repository,
+        remote_name: &str,
+        branch: &str,
+        progress: &ProgressBar,
+    ) -> Result<()> {
+        // Without a leading `+` the remote refuses non-fast-forward updates.
+        push(
+            repo,
+            remote_name,
+            branch,
+            &format!("refs/heads/{branch}:refs/heads/{branch}"),
+            None,
+            progress,
+        )
+    }
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("git push failed: {}", stderr);
+    fn push_force_with_lease(
+        &self,
+        repo: &Repository,
+        remote_name: &str,
+        branch: &str,
+        expected: Option<Oid>,
+        progress: &ProgressBar,
+    ) -> Result<()> {
+        // libgit2 has no lease of its own. The check runs while the push
+        // negotiates, on the connection that then sends the update along
+        // with the old value it saw, so nothing can land in between.
+        let refname = format!("refs/heads/{branch}");
+        push(
+            repo,
+            remote_name,
+            branch,
+            &format!("+{refname}:{refname}"),
+            Some(expected.unwrap_or_else(Oid::zero)),
+            progress,
+        )
     }
+}
 
+/// Pushes `refspec`. With a `lease`, the push is refused unless the remote
+/// ref is at that oid when the push negotiates; zero means it must not exist.
+fn push(
+    repo: &Repository,
+    remote_name: &str,
+    branch: &str,
+    refspec: &str,
+    lease: Option<Oid>,
+    progress: &ProgressBar,
+) -> Result<()> {
+    let config = repo.config()?;
+    let mut remote = repo.find_remote(remote_name)?;
+    let label = progress.message();
+    // A rejected ref doesn't fail the push itself; it is only reported to
+    // the update-reference callback.
+    let rejection = RefCell::new(None);
+    let mut callbacks = callbacks(&config);
+    callbacks.pack_progress(|_stage, current, total| {
+        if total > 0 {
+            progress.set_message(format!("{label} — packing {current}/{total} objects"));
+        }
+    });
+    callbacks.push_transfer_progress(|current, total, bytes| {
+        progress.set_message(format!(
+            "{label} — sent {current}/{total} objects, {}",
+            HumanBytes(bytes as u64)
+        ));
+    });
+    callbacks.push_negotiation(|updates| {
+        let Some(expected) = lease else {
+            return Ok(());
+        };
+        let Some(update) = updates.iter().find(|update| update.src() != expected) else {
+            return Ok(());
+        };
+        let describe = |oid: Oid| {
+            if oid.is_zero() {
+                "missing".to_string()
+            } else {
+                oid.to_string()
+            }
+        };
+        *rejection.borrow_mut() = Some(format!(
+            "stale lease: expected {}, remote has {}",
+            describe(expected),
+            describe(update.src())
+        ));
+        Err(git2::Error::from_str("stale lease"))
+    });
+    callbacks.push_update_reference(|_refname, status| {
+        if let Some(message) = status {
+            *rejection.borrow_mut() = Some(message.to_string());
+        }
+        Ok(())
+    });
+    let mut options = PushOptions::new();
+    options.remote_callbacks(callbacks);
+    let pushed = remote.push(&[refspec], Some(&mut options));
+    // A stale lease fails the push itself; report it as the rejection it is.
+    if let Some(message) = rejection.take() {
+        return Err(GitxError::PushRejected {
+            remote: remote_name.to_string(),
+            branch: branch.to_string(),
+            message,
+        }
+        .into());
+    }
+    pushed
+        .map_err(|err| classify_git2_failure(err, remote_name, Some(branch)))
+        .with_context(|| format!("push {refspec} to {remote_name}"))?;
     Ok(())
 }
+
+fn classify_git2_failure(
+    err: git2::Error,
+    remote_name: &str,
+    branch: Option<&str>,
+) -> anyhow::Error {
+    match (err.code(), branch) {
+        (ErrorCode::Auth, _) => GitxError::AuthFailed {
+            remote: remote_name.to_string(),
+            message: err.message().to_string(),
+        }
+        .into(),
+        (ErrorCode::NotFastForward, Some(branch)) => GitxError::PushRejected {
+            remote: remote_name.to_string(),
+            branch: branch.to_string(),
+            message: err.message().to_string(),
+        }
+        .into(),
+        _ => err.into(),
+    }
+}
+
+/// Recognises authentication failures and rejected pushes in what the git
+/// CLI printed; `None` for anything else.
+fn classify_cli_failure(
+    stderr: &str,
+    remote_name: &str,
+    branch: Option<&str>,
+) -> Option<anyhow::Error> {
+    const AUTH: [&str; 4] = [
+        "Authentication failed",
+        "Permission denied",
+        "could not read Username",
+        "terminal prompts disabled",
+    ];
+    const REJECTED: [&str; 3] = ["[rejected]", "[remote rejected]", "stale info"];
+
+    let message = stderr.trim().to_string();
+    if AUTH.iter().any(|needle| stderr.contains(needle)) {
+        return Some(
+            GitxError::AuthFailed {
+                remote: remote_name.to_string(),
+                message,
+            }
+            .into(),
+        );
+    }
+    let branch = branch?;
+    REJECTED
+        .iter()
+        .any(|needle| stderr.contains(needle))
+        .then(|| {
+            GitxError::PushRejected {
+                remote: remote_name.to_string(),
+                branch: branch.to_string(),
+                message,
+            }
+            .into()
+        })
+}
+
+fn describe_fetch(stats: &git2::Progress<'_>) -> String {
+    if stats.total_objects() > 0 && stats.received_objects() == stats.total_objects() {
+        format!(
+            "indexing deltas {}/{}",
+            stats.indexed_deltas(),
+            stats.total_deltas()
+        )
+    } else {
+        format!(
+            "received {}/{} objects, {}",
+            stats.received_objects(),
+            stats.total_objects(),
+            HumanBytes(stats.received_bytes() as u64)
+        )
+    }
+}
+
+/// Callbacks that answer credential requests with, in turn: the SSH agent,
+/// a token from `GIT_HELPER_TOKEN`, and git's configured credential helper.
+/// Each source is offered once so a rejected credential fails instead of
+/// looping.
+fn callbacks(config: &git2::Config) -> RemoteCallbacks<'_> {
+    let mut tried = CredentialType::empty();
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| {
+        credentials(config, &mut tried, url, username, allowed)
+    });
+    callbacks
+}
+
+fn credentials(
+    config: &git2::Config,
+    tried: &mut CredentialType,
+    url: &str,
+    username: Option<&str>,
+    allowed: CredentialType,
+) -> Result<Cred, git2::Error> {
+    if allowed.contains(CredentialType::USERNAME) {
+        return Cred::username(username.unwrap_or("git"));
+    }
+    if allowed.contains(CredentialType::SSH_KEY) && !tried.contains(CredentialType::SSH_KEY) {
+        tried.insert(CredentialType::SSH_KEY);
+        return Cred::ssh_key_from_agent(username.unwrap_or("git"));
+    }
+    if allowed.contains(CredentialType::USER_PASS_PLAINTEXT)
+        && !tried.contains(CredentialType::USER_PASS_PLAINTEXT)
+    {
+        tried.insert(CredentialType::USER_PASS_PLAINTEXT);
+        if let Ok(token) = std::env::var(TOKEN_ENV) {
+            return Cred::userpass_plaintext(username.unwrap_or("x-access-token"), &token);
+        }
+        if let Ok(cred) = Cred::credential_helper(config, url, username) {
+            return Ok(cred);
+        }
+    }
+    if allowed.contains(CredentialType::DEFAULT) && !tried.contains(CredentialType::DEFAULT) {
+        tried.insert(CredentialType::DEFAULT);
+        return Cred::default();
+    }
+    Err(git2::Error::new(
+        ErrorCode::Auth,
+        ErrorClass::Net,
+        format!("no usable credentials for {url} (tried {tried:?})"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitx::test_support::TestRepo;
+    use tempfile::TempDir;
+
+    fn hidden() -> ProgressBar {
+        ProgressBar::hidden()
+    }
+
+    fn is_rejected(result: Result<()>) -> bool {
+        matches!(
+            result.unwrap_err().downcast_ref::<GitxError>(),
+            Some(GitxError::PushRejected { .. })
+        )
+    }
+
+    /// A clone-like setup: `local` with `origin` pointing at a bare repo
+    /// that already has `main`.
+    fn with_origin() -> Result<(TestRepo, TempDir)> {
+        let local = TestRepo::new()?;
+        local.commit_file("base.txt", "base\n", "base")?;
+        let bare_dir = tempfile::tempdir()?;
+        Repository::init_bare(bare_dir.path())?;
+        local
+            .repo
+            .remote("origin", bare_dir.path().to_str().unwrap())?;
+        Libgit2Transport.push_ff_only(&local.repo, "origin", "main", &hidden())?;
+        Ok((local, bare_dir))
+    }
+
+    fn remote_tip(bare: &TempDir, branch: &str) -> Option<Oid> {
+        let repo = Repository::open_bare(bare.path()).unwrap();
+        repo.refname_to_id(&format!("refs/heads/{branch}")).ok()
+    }
+
+    #[test]
+    fn test_libgit2_push_and_fetch_prune() -> Result<()> {
+        let (local, bare) = with_origin()?;
+        assert_eq!(remote_tip(&bare, "main"), Some(local.tip("main")?));
+
+        local.branch("feature")?;
+        Libgit2Transport.push_ff_only(&local.repo, "origin", "feature", &hidden())?;
+        assert!(
+            local
+                .repo
+                .find_reference("refs/remotes/origin/feature")
+                .is_ok()
+        );
+
+        let bare_repo = Repository::open_bare(bare.path())?;
+        bare_repo.find_reference("refs/heads/feature")?.delete()?;
+        Libgit2Transport.fetch_prune(&local.repo, "origin", &hidden())?;
+        assert!(
+            local
+                .repo
+                .find_reference("refs/remotes/origin/feature")
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_libgit2_push_ff_only_rejects_rewrite() -> Result<()> {
+        let (local, bare) = with_origin()?;
+        let pushed = local.commit_file("a.txt", "a\n", "a")?;
+        Libgit2Transport.push_ff_only(&local.repo, "origin", "main", &hidden())?;
+
+        // Rewrite the pushed commit locally.
+        let parent = local.repo.find_commit(pushed)?.parent(0)?;
+        local
+            .repo
+            .reset(parent.as_object(), git2::ResetType::Hard, None)?;
+        local.commit_file("a.txt", "b\n", "a rewritten")?;
+
+        assert!(is_rejected(Libgit2Transport.push_ff_only(
+            &local.repo,
+            "origin",
+            "main",
+            &hidden()
+        )));
+        assert_eq!(remote_tip(&bare, "main"), Some(pushed));
+        Ok(())
+    }
+
+    #[test]
+    fn test_libgit2_lease() -> Result<()> {
+        let (local, bare) = with_origin()?;
+        let before = local.tip("main")?;
+        let rewritten = local.commit_file("a.txt", "a\n", "a")?;
+
+        let stale = Oid::from_str("0123456789abcdef0123456789abcdef01234567")?;
+        assert!(is_rejected(Libgit2Transport.push_force_with_lease(
+            &local.repo,
+            "origin",
+            "main",
+            Some(stale),
+            &hidden()
+        )));
+        assert!(is_rejected(Libgit2Transport.push_force_with_lease(
+            &local.repo,
+            "origin",
+            "main",
+            None,
+            &hidden()
+        )));
+        assert_eq!(remote_tip(&bare, "main"), Some(before));
+
+        Libgit2Transport.push_force_with_lease(
+            &local.repo,
+            "origin",
+            "main",
+            Some(before),
+            &hidden(),
+        )?;
+        assert_eq!(remote_tip(&bare, "main"), Some(rewritten));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_progress_separates_progress_from_output() -> Result<()> {
+        let stderr = "From /tmp/upstream\n\
+                      remote: Enumerating objects: 5, done.\n\
+                      Receiving objects:  50% (1/2)\rReceiving objects: 100% (2/2), done.\n   \
+                      0e1b2c3..4d5e6f7  main       -> origin/main\n";
+        let progress = ProgressBar::hidden();
+        progress.set_message("fetch origin");
+
+        let rest = read_progress(stderr.as_bytes(), &progress)?;
+
+        assert_eq!(
+            rest,
+            "From /tmp/upstream\n   0e1b2c3..4d5e6f7  main       -> origin/main\n"
+        );
+        assert_eq!(
+            progress.message(),
+            "fetch origin — Receiving objects: 100% (2/2), done."
+        );
+        assert!(!is_progress("error: failed to push some refs to 'origin'"));
+        assert!(!is_progress(
+            " ! [rejected]        main -> main (fetch first)"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cli_push_and_fetch_prune() -> Result<()> {
+        let (local, bare) = with_origin()?;
+        local.commit_file("more.txt", "more\n", "more")?;
+        let progress = hidden();
+        progress.set_message("push main");
+        CliTransport.push_ff_only(&local.repo, "origin", "main", &progress)?;
+        assert_eq!(remote_tip(&bare, "main"), Some(local.tip("main")?));
+
+        CliTransport.fetch_prune(&local.repo, "origin", &hidden())?;
+        assert_eq!(
+            local.repo.refname_to_id("refs/remotes/origin/main")?,
+            local.tip("main")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_classify_cli_failure() {
+        let auth = classify_cli_failure(
+            "git@github.com: Permission denied (publickey).\nfatal: Could not read from remote repository.",
+            "origin",
+            None,
+        );
+        assert!(matches!(
+            auth.unwrap().downcast_ref::<GitxError>(),
+            Some(GitxError::AuthFailed { .. })
+        ));
+
+        let rejected = classify_cli_failure(
+            " ! [rejected]        main -> main (non-fast-forward)",
+            "origin",
+            Some("main"),
+        );
+        assert!(matches!(
+            rejected.unwrap().downcast_ref::<GitxError>(),
+            Some(GitxError::PushRejected { .. })
+        ));
+
+        assert!(classify_cli_failure("fatal: unable to access", "origin", Some("main")).is_none());
+    }
+}
@@ -1,5 +1,11 @@
+use super::{
+    Backup, BranchStatus, Expiry, GitxError, MergeOutcome, PatchIds, RebaseOutcome,
+    SimulatedRebase, TransportBackend, UnstashOutcome,
+};
 use anyhow::{Context, Result};
 use git2::{BranchType, Oid, Repository};
+use indicatif::ProgressBar;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 pub struct OpenRepoOpts {
@@ -12,21 +18,53 @@ pub struct GitRepo {
 
 impl GitRepo {
     pub fn discover(opts: OpenRepoOpts) -> Result<Self> {
-        let repo = Repository::discover(opts.workdir)?;
+        let repo = Repository::discover(&opts.workdir).map_err(|err| match err.code() {
+            git2::ErrorCode::NotFound => GitxError::NotARepo {
+                path: opts.workdir.display().to_string(),
+            }
+            .into(),
+            _ => anyhow::Error::from(err),
+        })?;
         Ok(Self { inner: repo })
     }
 
     pub fn current_branch_name(&self) -> Result<String> {
+        if self.inner.head_detached()? {
+            return Err(GitxError::DetachedHead.into());
+        }
         let head = self.inner.head()?;
-        let name = head
-            .shorthand()
-            .ok_or_else(|| anyhow::anyhow!("detached HEAD"))?;
+        let name = head.shorthand().context("branch name is not valid UTF-8")?;
         Ok(name.to_string())
     }
 
+    /// `origin` if there is one, else the only remote, else `origin` anyway.
     pub fn default_remote(&self) -> Result<String> {
-        // TODO: read from config; fallback to "origin"
-        Ok("origin".into())
+        let remotes = self.remote_names()?;
+        match remotes.as_slice() {
+            [only] => Ok(only.clone()),
+            _ => Ok("origin".into()),
+        }
+    }
+
+    pub fn remote_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .inner
+            .remotes()?
+            .iter()
+            .flatten()
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Every remote with a fetch URL, as `(name, url)`.
+    pub fn remote_urls(&self) -> Result<Vec<(String, String)>> {
+        let mut urls = vec![];
+        for name in self.remote_names()? {
+            if let Some(url) = self.inner.find_remote(&name)?.url() {
+                urls.push((name, url.to_string()));
+            }
+        }
+        Ok(urls)
     }
 
     pub fn remote_head_default_branch(&self, remote: &str) -> Result<String> {
@@ -41,45 +79,305 @@ impl GitRepo {
         Ok(name.to_string())
     }
 
-    pub fn is_ff_up_to_remote(&self, branch: &str, remote_name: &str) -> Result<bool> {
-        let local = self
-            .branch_tip(branch, BranchType::Local)
-            .context("local branch tip")?;
-        let remote = self
-            .branch_tip(&format!("{remote_name}/{branch}"), BranchType::Remote)
-            .context("remote branch tip")?;
-        let base = self.inner.merge_base(local, remote)?;
-        Ok(base == remote) // remote is ancestor of local -> local contains remote
+    pub fn branch_status(&self, branch: &str, remote_name: &str) -> Result<BranchStatus> {
+        super::refs::branch_status(&self.inner, branch, remote_name)
+    }
+
+    /// Tip of the remote-tracking branch `<remote_name>/<branch>`.
+    pub fn remote_tip(&self, branch: &str, remote_name: &str) -> Result<Oid> {
+        self.branch_tip(&format!("{remote_name}/{branch}"), BranchType::Remote)
+    }
+
+    pub fn local_tip(&self, branch: &str) -> Result<Oid> {
+        self.branch_tip(branch, BranchType::Local)
+    }
+
+    /// Whether `target` is `tip` or one of its ancestors.
+    pub fn is_descendant(&self, tip: Oid, target: Oid) -> Result<bool> {
+        Ok(tip == target || self.inner.graph_descendant_of(tip, target)?)
+    }
+
+    pub fn is_merged(&self, branch: Oid, main: Oid) -> Result<bool> {
+        super::merged::is_merged(&self.inner, branch, main)
+    }
+
+    /// Whether `branch` was squash-merged into `main`; `main_ids` carries
+    /// main's patch ids from one branch to the next.
+    pub fn is_squash_merged(
+        &self,
+        branch: Oid,
+        main: Oid,
+        main_ids: &mut PatchIds,
+    ) -> Result<bool> {
+        super::merged::is_squash_merged(&self.inner, branch, main, main_ids)
+    }
+
+    /// Whether one of `remote`'s branches already has `branch`.
+    pub fn is_on_remote(&self, branch: Oid, remote: &str) -> Result<bool> {
+        super::merged::is_on_remote(&self.inner, branch, remote)
+    }
+
+    /// Commits on `tip` that `upstream` lacks, oldest first.
+    pub fn local_only(&self, upstream: Oid, tip: Oid) -> Result<Vec<Oid>> {
+        super::merged::local_only(&self.inner, upstream, tip)
+    }
+
+    /// Commits on `tip` since it forked from `onto` whose change `onto`
+    /// already has.
+    pub fn upstream_equivalents(&self, onto: Oid, tip: Oid) -> Result<Vec<Oid>> {
+        super::merged::upstream_equivalents(&self.inner, onto, tip)
+    }
+
+    /// Whether `branch` is checked out in a worktree other than this one.
+    pub fn checked_out_elsewhere(&self, branch: &str) -> Result<bool> {
+        let refname = format!("refs/heads/{branch}");
+        let here = !self.inner.head_detached()? && self.inner.head()?.name() == Some(&refname);
+        Ok(!here && super::refs::checkout_of(&self.inner, &refname)?.is_some())
+    }
+
+    pub fn delete_branch(&self, branch: &str, expected: Oid) -> Result<()> {
+        super::refs::delete_branch(&self.inner, branch, expected)
     }
 
-    pub fn is_branch_ancestor_of(&self, child: &str, parent: &str) -> Result<bool> {
-        let child_oid = self.branch_tip(child, BranchType::Local)?;
-        let parent_oid = self
-            .branch_tip(parent, BranchType::Remote)
-            .or_else(|_| self.branch_tip(&format!("origin/{parent}"), BranchType::Remote))?;
-        let base = self.inner.merge_base(child_oid, parent_oid)?;
-        Ok(base == child_oid)
+    /// Saves `tip` as a backup of `branch`, returning the backup ref.
+    pub fn backup_branch(&self, branch: &str, tip: Oid) -> Result<String> {
+        super::backup::create(&self.inner, branch, tip)
     }
 
-    pub fn fetch_prune(&self, remote: &str) -> Result<()> {
-        super::remote::fetch_prune(&self.inner, remote)
+    /// Every backup ref, oldest first.
+    pub fn backups(&self) -> Result<Vec<Backup>> {
+        super::backup::list(&self.inner)
     }
 
-    pub fn fast_forward_branch(&self, branch: &str) -> Result<()> {
-        super::refs::fast_forward_to_upstream(&self.inner, branch)
+    /// A backup by ref name, `<time>/<branch>`, or branch (its newest).
+    pub fn find_backup(&self, spec: &str) -> Result<Backup> {
+        super::backup::find(&self.inner, spec)
+    }
+
+    /// Backups that `expiry` no longer keeps.
+    pub fn expired_backups(&self, expiry: Expiry) -> Result<Vec<Backup>> {
+        let now = super::backup::now()?;
+        Ok(super::backup::expired(&self.backups()?, expiry, now))
+    }
+
+    pub fn delete_backup(&self, backup: &Backup) -> Result<()> {
+        super::backup::delete(&self.inner, backup)
+    }
+
+    /// Moves the backup's branch back to it, returning the backup of where
+    /// the branch was, if it moved.
+    pub fn restore_backup(&self, backup: &Backup) -> Result<Option<String>> {
+        super::backup::restore(&self.inner, backup)
+    }
+
+    /// Resets `branch` to `<remote>/<branch>`, returning the backup ref its
+    /// old tip was saved under.
+    pub fn reset_to_upstream(&self, branch: &str, remote: &str, expected: Oid) -> Result<String> {
+        super::refs::reset_to_upstream(&self.inner, branch, remote, expected)
+    }
+
+    pub fn tracked_branches(&self, remote: &str) -> Result<Vec<String>> {
+        super::refs::tracked_branches(&self.inner, remote)
+    }
+
+    /// Boolean `branch.<branch>.<key>` from git config, if set.
+    pub fn branch_config_bool(&self, branch: &str, key: &str) -> Result<Option<bool>> {
+        match self
+            .inner
+            .config()?
+            .get_bool(&format!("branch.{branch}.{key}"))
+        {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn fetch_prune(
+        &self,
+        backend: TransportBackend,
+        remote: &str,
+        progress: &ProgressBar,
+    ) -> Result<()> {
+        backend
+            .transport()
+            .fetch_prune(&self.inner, remote, progress)
+    }
+
+    pub fn fast_forward_branch(&self, branch: &str, remote: &str, expected: Oid) -> Result<()> {
+        super::refs::fast_forward_branch(&self.inner, branch, remote, expected)
     }
 
     pub fn rebase_onto(
         &self,
         src_branch: &str,
         onto_branch: &str,
+        upstream: Option<Oid>,
         non_interactive: bool,
+    ) -> Result<RebaseOutcome> {
+        super::rebase::rebase_onto(
+            &self.inner,
+            src_branch,
+            onto_branch,
+            upstream,
+            non_interactive,
+        )
+    }
+
+    /// Fork point of `tip` from the history recorded in `reflog_ref`'s
+    /// reflog, if any logged value is in `tip`.
+    pub fn reflog_fork_point(&self, reflog_ref: &str, tip: Oid) -> Result<Option<Oid>> {
+        super::rebase::reflog_fork_point(&self.inner, reflog_ref, tip)
+    }
+
+    pub fn rebase_branch(
+        &self,
+        src_branch: &str,
+        upstream: Oid,
+        onto: Oid,
+    ) -> Result<RebaseOutcome> {
+        super::rebase::rebase_branch(&self.inner, src_branch, upstream, onto)
+    }
+
+    /// What rebasing `upstream..tip` onto `onto` would do, without
+    /// changing anything a user can see.
+    pub fn simulate_rebase(&self, upstream: Oid, onto: Oid, tip: Oid) -> Result<SimulatedRebase> {
+        super::rebase::simulate_rebase(&self.inner, upstream, onto, tip)
+    }
+
+    /// What merging `theirs` into `ours` would produce, and its conflicts.
+    pub fn simulate_merge(
+        &self,
+        ours: Oid,
+        theirs: Oid,
+        message: &str,
+    ) -> Result<(Oid, Option<Vec<String>>)> {
+        super::merge::simulate_merge(&self.inner, ours, theirs, message)
+    }
+
+    /// Best common ancestor of two commits.
+    pub fn merge_base(&self, a: Oid, b: Oid) -> Result<Oid> {
+        Ok(self.inner.merge_base(a, b)?)
+    }
+
+    pub fn create_branch(&self, name: &str, at: Oid) -> Result<()> {
+        let commit = self.inner.find_commit(at)?;
+        self.inner.branch(name, &commit, false)?;
+        Ok(())
+    }
+
+    /// Check out an existing local branch, refusing to clobber local edits.
+    pub fn switch_to(&self, branch: &str) -> Result<()> {
+        let refname = format!("refs/heads/{branch}");
+        let tip = self.branch_tip(branch, BranchType::Local)?;
+        self.inner
+            .checkout_tree(&self.inner.find_object(tip, None)?, None)?;
+        self.inner.set_head(&refname)?;
+        Ok(())
+    }
+
+    pub fn rebase_in_progress(&self) -> bool {
+        super::rebase::in_progress(&self.inner)
+    }
+
+    pub fn continue_rebase(&self) -> Result<RebaseOutcome> {
+        super::rebase::continue_rebase(&self.inner)
+    }
+
+    pub fn skip_rebase(&self) -> Result<RebaseOutcome> {
+        super::rebase::skip_rebase(&self.inner)
+    }
+
+    pub fn abort_rebase(&self) -> Result<()> {
+        super::rebase::abort_rebase(&self.inner)
+    }
+
+    /// Merges `from` into `branch` with a merge commit titled `message`.
+    pub fn merge_from(&self, branch: &str, from: &str, message: &str) -> Result<MergeOutcome> {
+        super::merge::merge_from(&self.inner, branch, from, message)
+    }
+
+    pub fn merge_in_progress(&self) -> bool {
+        super::merge::in_progress(&self.inner)
+    }
+
+    pub fn continue_merge(&self) -> Result<MergeOutcome> {
+        super::merge::continue_merge(&self.inner)
+    }
+
+    pub fn abort_merge(&self) -> Result<()> {
+        super::merge::abort_merge(&self.inner)
+    }
+
+    pub fn push_if_ff(
+        &self,
+        backend: TransportBackend,
+        remote: &str,
+        branch: &str,
+        progress: &ProgressBar,
+    ) -> Result<()> {
+        backend
+            .transport()
+            .push_ff_only(&self.inner, remote, branch, progress)
+    }
+
+    pub fn push_with_lease(
+        &self,
+        backend: TransportBackend,
+        remote: &str,
+        branch: &str,
+        expected: Option<Oid>,
+        progress: &ProgressBar,
     ) -> Result<()> {
-        super::rebase::rebase_onto(&self.inner, src_branch, onto_branch, non_interactive)
+        backend
+            .transport()
+            .push_force_with_lease(&self.inner, remote, branch, expected, progress)
+    }
+
+    /// The worktree's path, for messages; empty for a bare repository.
+    pub fn workdir(&self) -> String {
+        self.inner
+            .workdir()
+            .map(|path| path.display().to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn is_dirty(&self) -> Result<bool> {
+        super::stash::is_dirty(&self.inner)
+    }
+
+    pub fn autostash(&self) -> Result<Oid> {
+        super::stash::autostash(&self.inner)
+    }
+
+    pub fn restore_autostash(&self) -> Result<UnstashOutcome> {
+        super::stash::restore_autostash(&self.inner)
+    }
+
+    /// Commit a direct ref points at, or `None` if it doesn't exist.
+    pub fn ref_target(&self, name: &str) -> Result<Option<Oid>> {
+        super::refs::ref_target(&self.inner, name)
+    }
+
+    pub fn local_branch_tips(&self) -> Result<BTreeMap<String, Oid>> {
+        super::refs::local_branch_tips(&self.inner)
+    }
+
+    pub fn head_state(&self) -> Result<String> {
+        super::refs::head_state(&self.inner)
+    }
+
+    pub fn restore_ref(&self, name: &str, oid: Option<Oid>) -> Result<()> {
+        super::refs::restore_ref(&self.inner, name, oid, "git-helper: undo")
+    }
+
+    pub fn restore_head(&self, state: &str) -> Result<()> {
+        super::refs::restore_head(&self.inner, state)
     }
 
-    pub fn push_if_ff(&self, remote: &str, branch: &str) -> Result<()> {
-        super::remote::push_ff_only(&self.inner, remote, branch)
+    /// Directory for git-helper's own state, shared by all worktrees.
+    pub fn state_dir(&self) -> PathBuf {
+        self.inner.commondir().join("git-helper")
     }
 
     fn branch_tip(&self, name: &str, kind: BranchType) -> Result<Oid> {
@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use git2::{ErrorCode, Oid, Repository, StashApplyOptions, StatusOptions};
+
+/// Message used for stashes created by `sync`, so people can tell them
+/// apart in `git stash list`.
+pub const AUTOSTASH_MESSAGE: &str = "git-helper autostash";
+
+/// File in the worktree's git dir holding the id of the stash entry the
+/// last `autostash` made, so it can be restored even after a run stopped
+/// halfway (e.g. on a rebase conflict), and never mixed up with an older
+/// entry that has the same message.
+const AUTOSTASH_FILE: &str = "git-helper-autostash";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnstashOutcome {
+    Restored,
+    /// Nothing to restore; no autostash entry exists.
+    Missing,
+    /// Reapplying clashed with the new tree; the entry is kept at `stash@{index}`
+    /// and any conflicts it produced are left in the worktree.
+    Conflicted {
+        index: usize,
+    },
+}
+
+/// Whether tracked files differ from HEAD in the index or worktree.
+/// Untracked files are ignored, as they survive checkouts and rebases.
+pub fn is_dirty(repo: &Repository) -> Result<bool> {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(false).include_ignored(false);
+    let statuses = repo.statuses(Some(&mut opts))?;
+    Ok(!statuses.is_empty())
+}
+
+pub fn autostash(repo: &Repository) -> Result<Oid> {
+    // Stashing needs a mutable handle; open a second one on the same repo.
+    let mut repo = Repository::open(repo.path())?;
+    let sig = repo.signature()?;
+    let oid = repo
+        .stash_save(&sig, AUTOSTASH_MESSAGE, None)
+        .context("stash uncommitted changes")?;
+    std::fs::write(repo.path().join(AUTOSTASH_FILE), format!("{oid}\n"))
+        .context("record autostash")?;
+    Ok(oid)
+}
+
+/// The stash index of the entry the last `autostash` made, if it hasn't
+/// been restored yet.
+pub fn find_autostash(repo: &Repository) -> Result<Option<usize>> {
+    let Some(oid) = recorded_autostash(repo)? else {
+        return Ok(None);
+    };
+    let mut repo = Repository::open(repo.path())?;
+    let mut found = None;
+    repo.stash_foreach(|index, _message, stash| {
+        if *stash == oid {
+            found = Some(index);
+            return false;
+        }
+        true
+    })?;
+    Ok(found)
+}
+
+fn recorded_autostash(repo: &Repository) -> Result<Option<Oid>> {
+    match std::fs::read_to_string(repo.path().join(AUTOSTASH_FILE)) {
+        Ok(text) => Ok(Some(Oid::from_str(text.trim())?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("read autostash record"),
+    }
+}
+
+fn forget_autostash(repo: &Repository) -> Result<()> {
+    match std::fs::remove_file(repo.path().join(AUTOSTASH_FILE)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).context("remove autostash record")
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn restore_autostash(repo: &Repository) -> Result<UnstashOutcome> {
+    let Some(index) = find_autostash(repo)? else {
+        forget_autostash(repo)?;
+        return Ok(UnstashOutcome::Missing);
+    };
+    // Whatever happens next, this entry has had its one chance to be
+    // restored automatically.
+    forget_autostash(repo)?;
+    let mut handle = Repository::open(repo.path())?;
+    let mut opts = StashApplyOptions::new();
+    opts.reinstantiate_index();
+    // Apply and drop separately, like `git stash pop`: an entry that
+    // reapplies with conflicts must stay around.
+    match handle.stash_apply(index, Some(&mut opts)) {
+        Ok(()) => {}
+        Err(e) if matches!(e.code(), ErrorCode::Conflict | ErrorCode::MergeConflict) => {
+            return Ok(UnstashOutcome::Conflicted { index });
+        }
+        Err(e) => return Err(e).context("restore autostash"),
+    }
+    if handle.index()?.has_conflicts() {
+        return Ok(UnstashOutcome::Conflicted { index });
+    }
+    handle.stash_drop(index)?;
+    Ok(UnstashOutcome::Restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitx::test_support::TestRepo;
+
+    #[test]
+    fn test_autostash_round_trip() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("file.txt", "base\n", "base")?;
+        std::fs::write(test_repo.path().join("file.txt"), "dirty\n")?;
+        assert!(is_dirty(&test_repo.repo)?);
+
+        autostash(&test_repo.repo)?;
+        assert!(!is_dirty(&test_repo.repo)?);
+        assert_eq!(find_autostash(&test_repo.repo)?, Some(0));
+
+        assert_eq!(
+            restore_autostash(&test_repo.repo)?,
+            UnstashOutcome::Restored
+        );
+        let content = std::fs::read_to_string(test_repo.path().join("file.txt"))?;
+        assert_eq!(content, "dirty\n");
+        assert_eq!(find_autostash(&test_repo.repo)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicting_autostash_is_kept() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("file.txt", "base\n", "base")?;
+        std::fs::write(test_repo.path().join("file.txt"), "dirty\n")?;
+        autostash(&test_repo.repo)?;
+        test_repo.commit_file("file.txt", "upstream\n", "upstream change")?;
+
+        assert_eq!(
+            restore_autostash(&test_repo.repo)?,
+            UnstashOutcome::Conflicted { index: 0 }
+        );
+        // Left in `git stash list` for the user, but never applied twice.
+        let mut handle = Repository::open(test_repo.path())?;
+        let mut kept = 0;
+        handle.stash_foreach(|_, _, _| {
+            kept += 1;
+            true
+        })?;
+        assert_eq!(kept, 1);
+        assert_eq!(find_autostash(&test_repo.repo)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_this_runs_autostash_is_restored() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("file.txt", "base\n", "base")?;
+        // Left over from a run that crashed before recording it.
+        std::fs::write(test_repo.path().join("file.txt"), "stale\n")?;
+        let mut handle = Repository::open(test_repo.path())?;
+        let sig = handle.signature()?;
+        handle.stash_save(&sig, AUTOSTASH_MESSAGE, None)?;
+
+        assert_eq!(find_autostash(&test_repo.repo)?, None);
+        assert_eq!(restore_autostash(&test_repo.repo)?, UnstashOutcome::Missing);
+
+        std::fs::write(test_repo.path().join("other.txt"), "mine\n")?;
+        test_repo.stage_file("other.txt", "mine\n")?;
+        autostash(&test_repo.repo)?;
+        // Someone stashes on top of it before it's restored.
+        std::fs::write(test_repo.path().join("file.txt"), "later\n")?;
+        handle.stash_save(&sig, "WIP", None)?;
+        assert_eq!(find_autostash(&test_repo.repo)?, Some(1));
+
+        assert_eq!(
+            restore_autostash(&test_repo.repo)?,
+            UnstashOutcome::Restored
+        );
+        let restored = std::fs::read_to_string(test_repo.path().join("other.txt"))?;
+        assert_eq!(restored, "mine\n");
+        let mut messages = vec![];
+        handle.stash_foreach(|_, message, _| {
+            messages.push(message.to_string());
+            true
+        })?;
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].ends_with("WIP"));
+        assert!(messages[1].ends_with(AUTOSTASH_MESSAGE));
+        Ok(())
+    }
+
+    #[test]
+    fn test_untracked_files_are_not_dirty() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        test_repo.commit_file("file.txt", "base\n", "base")?;
+        std::fs::write(test_repo.path().join("new.txt"), "new\n")?;
+        assert!(!is_dirty(&test_repo.repo)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::Result;
+use git2::{BranchType, Oid, Repository, RepositoryInitOptions};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Scratch repository for unit tests, initialized on a `main` branch with a
+/// committer identity so tests don't depend on the user's git config.
+pub struct TestRepo {
+    _temp_dir: TempDir,
+    pub repo: Repository,
+}
+
+impl TestRepo {
+    pub fn new() -> Result<Self> {
+        let temp_dir = tempfile::tempdir()?;
+        let repo = Repository::init_opts(
+            temp_dir.path(),
+            RepositoryInitOptions::new().initial_head("main"),
+        )?;
+        repo.config()?.set_str("user.name", "Test User")?;
+        repo.config()?.set_str("user.email", "test@example.com")?;
+        Ok(Self {
+            _temp_dir: temp_dir,
+            repo,
+        })
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.repo.workdir().unwrap().to_path_buf()
+    }
+
+    pub fn stage_file(&self, path: &str, content: &str) -> Result<()> {
+        std::fs::write(self.path().join(path), content)?;
+        let mut index = self.repo.index()?;
+        index.add_path(Path::new(path))?;
+        index.write()?;
+        Ok(())
+    }
+
+    pub fn commit_file(&self, path: &str, content: &str, message: &str) -> Result<Oid> {
+        self.stage_file(path, content)?;
+        let sig = self.repo.signature()?;
+        let tree = self.repo.find_tree(self.repo.index()?.write_tree()?)?;
+        let parent = self.repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let parents: Vec<_> = parent.iter().collect();
+        Ok(self
+            .repo
+            .commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)?)
+    }
+
+    pub fn branch(&self, name: &str) -> Result<()> {
+        let head = self.repo.head()?.peel_to_commit()?;
+        self.repo.branch(name, &head, false)?;
+        Ok(())
+    }
+
+    pub fn checkout(&self, name: &str) -> Result<()> {
+        let refname = format!("refs/heads/{name}");
+        let obj = self.repo.revparse_single(&refname)?;
+        self.repo
+            .checkout_tree(&obj, Some(git2::build::CheckoutBuilder::new().force()))?;
+        self.repo.set_head(&refname)?;
+        Ok(())
+    }
+
+    pub fn tip(&self, name: &str) -> Result<Oid> {
+        let branch = self.repo.find_branch(name, BranchType::Local)?;
+        Ok(branch.get().target().unwrap())
+    }
+}
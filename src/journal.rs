@@ -0,0 +1,291 @@
+use crate::gitx::GitRepo;
+use anyhow::{Context, Result};
+use git2::Oid;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Record of the local refs a single `sync` run moved, stored as
+/// `.git/git-helper/journal/<id>.toml` so the run can be undone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Journal {
+    pub id: String,
+    /// HEAD before the run: a ref name, or a commit id when detached.
+    pub head_before: String,
+    #[serde(default)]
+    pub undone: bool,
+    #[serde(default)]
+    pub entries: Vec<JournalEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub op: String,
+    pub refs: Vec<RefChange>,
+}
+
+/// A ref update; `None` means the ref did not exist on that side.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RefChange {
+    pub name: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl Journal {
+    /// The value each touched ref had before the run started.
+    pub fn original_refs(&self) -> BTreeMap<String, Option<String>> {
+        let mut original = BTreeMap::new();
+        for entry in &self.entries {
+            for change in &entry.refs {
+                original
+                    .entry(change.name.clone())
+                    .or_insert_with(|| change.before.clone());
+            }
+        }
+        original
+    }
+
+    /// The value each touched ref had when the run finished.
+    pub fn final_refs(&self) -> BTreeMap<String, Option<String>> {
+        let mut last = BTreeMap::new();
+        for entry in &self.entries {
+            for change in &entry.refs {
+                last.insert(change.name.clone(), change.after.clone());
+            }
+        }
+        last
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("create journal dir {}", dir.display()))?;
+        let path = dir.join(format!("{}.toml", self.id));
+        std::fs::write(&path, toml::to_string(self)?)
+            .with_context(|| format!("write journal {}", path.display()))
+    }
+
+    pub fn load(dir: &Path, id: &str) -> Result<Self> {
+        let path = dir.join(format!("{id}.toml"));
+        let text =
+            std::fs::read_to_string(&path).with_context(|| format!("no journal for run {id}"))?;
+        toml::from_str(&text).with_context(|| format!("parse journal {}", path.display()))
+    }
+
+    /// All recorded runs, oldest first.
+    pub fn list(dir: &Path) -> Result<Vec<Self>> {
+        let mut ids = vec![];
+        if dir.exists() {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "toml")
+                    && let Some(stem) = path.file_stem().and_then(|s| s.to_str())
+                {
+                    ids.push(stem.to_string());
+                }
+            }
+        }
+        // Ids are zero-padded timestamps, so lexical order is chronological.
+        ids.sort();
+        ids.iter().map(|id| Self::load(dir, id)).collect()
+    }
+}
+
+pub fn journal_dir(repo: &GitRepo) -> PathBuf {
+    repo.state_dir().join("journal")
+}
+
+/// File in the worktree's git dir naming the run a conflict stopped, so
+/// `sync --continue`/`--skip` add their moves to that run's journal.
+const STOPPED_RUN_FILE: &str = "git-helper-stopped-run";
+
+/// Snapshots local branches around each op and appends whatever
+/// moved to the run's journal, saving after every op so a failed or
+/// interrupted run is still recorded.
+pub struct Recorder<'a> {
+    repo: &'a GitRepo,
+    dir: PathBuf,
+    journal: Journal,
+}
+
+impl<'a> Recorder<'a> {
+    pub fn start(repo: &'a GitRepo) -> Result<Self> {
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        Ok(Self {
+            repo,
+            dir: journal_dir(repo),
+            journal: Journal {
+                id: format!("{millis:016}"),
+                head_before: repo.head_state()?,
+                undone: false,
+                entries: vec![],
+            },
+        })
+    }
+
+    /// Picks up the run a conflict stopped in this worktree, or starts a
+    /// new one if none is recorded.
+    pub fn resume(repo: &'a GitRepo) -> Result<Self> {
+        let dir = journal_dir(repo);
+        let id = match std::fs::read_to_string(repo.inner.path().join(STOPPED_RUN_FILE)) {
+            Ok(id) => id.trim().to_string(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Self::start(repo),
+            Err(e) => return Err(e).context("read stopped run"),
+        };
+        let journal = Journal::load(&dir, &id)?;
+        Ok(Self { repo, dir, journal })
+    }
+
+    /// Marks the run as stopped on conflicts, for `resume` to pick up.
+    pub fn stop(&self) -> Result<()> {
+        self.journal.save(&self.dir)?;
+        std::fs::write(
+            self.repo.inner.path().join(STOPPED_RUN_FILE),
+            format!("{}\n", self.journal.id),
+        )
+        .context("record stopped run")
+    }
+
+    /// Ends a stop once its rebase or merge is done or aborted. A run that
+    /// moved nothing leaves no journal, as if it never stopped.
+    pub fn finish(&self) -> Result<()> {
+        match std::fs::remove_file(self.repo.inner.path().join(STOPPED_RUN_FILE)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).context("remove stopped run record");
+            }
+            _ => {}
+        }
+        let path = self.dir.join(format!("{}.toml", self.journal.id));
+        if self.journal.entries.is_empty() && path.exists() {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("remove empty journal {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    pub fn record<T>(&mut self, op: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let before = self.repo.local_branch_tips()?;
+        let result = f();
+        let after = self.repo.local_branch_tips()?;
+
+        let refs = diff_tips(&before, &after);
+        if !refs.is_empty() {
+            self.journal.entries.push(JournalEntry {
+                op: op.to_string(),
+                refs,
+            });
+            self.journal.save(&self.dir)?;
+        }
+        result
+    }
+}
+
+fn diff_tips(before: &BTreeMap<String, Oid>, after: &BTreeMap<String, Oid>) -> Vec<RefChange> {
+    let mut names: Vec<&String> = before.keys().chain(after.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter(|name| before.get(*name) != after.get(*name))
+        .map(|name| RefChange {
+            name: name.clone(),
+            before: before.get(name).map(Oid::to_string),
+            after: after.get(name).map(Oid::to_string),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitx::test_support::TestRepo;
+
+    fn oid(byte: u8) -> Oid {
+        Oid::from_bytes(&[byte; 20]).unwrap()
+    }
+
+    #[test]
+    fn test_diff_tips() {
+        let before = BTreeMap::from([
+            ("refs/heads/main".to_string(), oid(1)),
+            ("refs/heads/gone".to_string(), oid(2)),
+            ("refs/heads/same".to_string(), oid(3)),
+        ]);
+        let after = BTreeMap::from([
+            ("refs/heads/main".to_string(), oid(4)),
+            ("refs/heads/new".to_string(), oid(5)),
+            ("refs/heads/same".to_string(), oid(3)),
+        ]);
+
+        let changes = diff_tips(&before, &after);
+
+        let names: Vec<_> = changes.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["refs/heads/gone", "refs/heads/main", "refs/heads/new"]
+        );
+        assert_eq!(changes[0].after, None);
+        assert_eq!(changes[2].before, None);
+    }
+
+    #[test]
+    fn test_original_refs_keeps_first_before() {
+        let change = |before: u8, after: u8| RefChange {
+            name: "refs/heads/main".to_string(),
+            before: Some(oid(before).to_string()),
+            after: Some(oid(after).to_string()),
+        };
+        let journal = Journal {
+            id: "1".to_string(),
+            head_before: "refs/heads/main".to_string(),
+            undone: false,
+            entries: vec![
+                JournalEntry {
+                    op: "first".to_string(),
+                    refs: vec![change(1, 2)],
+                },
+                JournalEntry {
+                    op: "second".to_string(),
+                    refs: vec![change(2, 3)],
+                },
+            ],
+        };
+
+        let original = journal.original_refs();
+        assert_eq!(
+            original.get("refs/heads/main"),
+            Some(&Some(oid(1).to_string()))
+        );
+        assert_eq!(
+            journal.final_refs().get("refs/heads/main"),
+            Some(&Some(oid(3).to_string()))
+        );
+    }
+
+    #[test]
+    fn test_recorder_saves_moved_refs() -> Result<()> {
+        let test_repo = TestRepo::new()?;
+        let first = test_repo.commit_file("file.txt", "one\n", "one")?;
+        let repo = GitRepo {
+            inner: git2::Repository::open(test_repo.path())?,
+        };
+
+        let mut recorder = Recorder::start(&repo)?;
+        recorder.record("noop", || Ok(()))?;
+        recorder.record("commit", || {
+            test_repo.commit_file("file.txt", "two\n", "two")
+        })?;
+
+        let runs = Journal::list(&journal_dir(&repo))?;
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].head_before, "refs/heads/main");
+        assert_eq!(runs[0].entries.len(), 1);
+        assert_eq!(runs[0].entries[0].op, "commit");
+        assert_eq!(
+            runs[0].original_refs().get("refs/heads/main"),
+            Some(&Some(first.to_string()))
+        );
+        Ok(())
+    }
+}
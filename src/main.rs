@@ -1,9 +1,17 @@
 use clap::{Parser, Subcommand};
+use gitx::GitxError;
+use report::{OutputFormat, PartialSuccess, Report};
+use std::process::ExitCode;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
 mod commands;
 mod config;
 mod gitx;
+mod journal;
 mod plan;
+mod progress;
+mod report;
+mod stack;
 mod util;
 
 #[derive(Parser)]
@@ -14,6 +22,11 @@ struct Cli {
     #[arg(global = true, long)]
     config: Option<String>,
 
+    /// `json` prints one document with the plan, op results, config and
+    /// final branch states on stdout, and sends logs to stderr
+    #[arg(global = true, long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
     #[command(subcommand)]
     cmd: Cmd,
 }
@@ -29,29 +42,401 @@ enum Cmd {
 
         #[arg(long)]
         non_interactive: bool,
+
+        /// Resume a rebase or merge stopped on conflicts after resolving them
+        #[arg(long = "continue", conflicts_with_all = ["skip", "abort"])]
+        continue_: bool,
+
+        /// Drop the commit the rebase stopped on and carry on
+        #[arg(long, conflicts_with = "abort")]
+        skip: bool,
+
+        /// Unwind a stopped rebase or merge back to the original branch
+        #[arg(long)]
+        abort: bool,
+
+        /// Write the computed plan as JSON for `git-helper apply`
+        #[arg(long)]
+        plan_out: Option<String>,
+
+        /// Also fast-forward every local branch tracking the remote, and
+        /// rebase those with `branch.<name>.githelper-rebase = true` onto main
+        #[arg(long)]
+        all: bool,
+
+        /// Also delete branches that are merged, squash-merged or whose
+        /// upstream is gone
+        #[arg(long)]
+        clean: bool,
+
+        /// How to bring the current branch up to date with main, overriding
+        /// its branch policy; `merge` never rewrites published history
+        #[arg(long, value_enum)]
+        strategy: Option<config::Strategy>,
+        /// If main has diverged from the remote (e.g. it was force-pushed),
+        /// reset it to the remote, discarding its local-only commits; the
+        /// old tip is kept in a backup ref
+        #[arg(long)]
+        reset_main: bool,
+    },
+    /// Delete local branches that are merged, squash-merged or whose upstream is gone
+    Clean {
+        /// Also delete gone branches with commits that are in neither main nor the remote
+        #[arg(long)]
+        force: bool,
+    },
+    /// Manage stacks of branches built on top of each other
+    Stack {
+        #[command(subcommand)]
+        cmd: StackCmd,
+    },
+    /// Execute a plan saved with `sync --plan-out`, if the repo hasn't drifted
+    Apply { plan: String },
+    /// Show, read or edit git-helper's configuration
+    Config {
+        #[command(subcommand)]
+        cmd: ConfigCmd,
+    },
+    /// List, restore or prune the backups kept of rewritten branch tips
+    Backups {
+        #[command(subcommand)]
+        cmd: BackupsCmd,
+    },
+    /// Restore the local branches and HEAD touched by a previous sync run
+    Undo {
+        /// Journal id of the run to undo; defaults to the most recent one
+        run: Option<String>,
+
+        /// List recorded runs instead of undoing one
+        #[arg(long)]
+        list: bool,
+
+        /// Undo even if branches moved again after the run
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum StackCmd {
+    /// Create a branch on top of PARENT (default: the current branch) and switch to it
+    Create {
+        name: String,
+
+        #[arg(long)]
+        parent: Option<String>,
     },
+    /// Print every recorded stack as a tree
+    Show,
+    /// Move BRANCH onto a different parent in its stack
+    SetParent { branch: String, parent: String },
+    /// Forget BRANCH, stacking its children on its parent
+    Remove { branch: String },
+    /// Rebase stacked branches that no longer sit on their parent's tip
+    Restack,
 }
 
-fn main() -> anyhow::Result<()> {
+#[derive(Subcommand)]
+enum BackupsCmd {
+    /// Print every backup, oldest first
+    List {
+        /// Only the backups of this branch
+        #[arg(long)]
+        branch: Option<String>,
+    },
+    /// Move a branch back to a backup: `<time>/<branch>` as listed, a full
+    /// ref name, or a branch name for its newest backup
+    Restore { backup: String },
+    /// Delete backups older than `backup.max_age_days`, or beyond
+    /// `backup.max_count` per branch
+    Prune,
+}
+
+#[derive(Subcommand)]
+enum ConfigCmd {
+    /// Print every effective setting and where it came from
+    Show,
+    /// Print the effective value of KEY, e.g. `defaults.main`
+    Get { key: String },
+    /// Set KEY to VALUE in one config file (default: .git/git-helper.toml)
+    Set {
+        key: String,
+        value: String,
+        #[command(flatten)]
+        scope: ScopeArgs,
+    },
+    /// Write a commented config file for this repository
+    Init {
+        #[command(flatten)]
+        scope: ScopeArgs,
+
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(clap::Args)]
+#[group(multiple = false)]
+struct ScopeArgs {
+    /// Use ~/.config/git-helper/config.toml, shared by every repository
+    #[arg(long)]
+    global: bool,
+
+    /// Use .git-helper.toml at the worktree root, usually committed
+    #[arg(long)]
+    repo: bool,
+
+    /// Use .git/git-helper.toml, private to this clone (the default)
+    #[arg(long)]
+    local: bool,
+}
+
+impl ScopeArgs {
+    fn scope(&self) -> config::Scope {
+        match (self.global, self.repo) {
+            (true, _) => config::Scope::Global,
+            (_, true) => config::Scope::Repo,
+            _ => config::Scope::Local,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    // Keep stdout for the JSON document; progress would only get in the way.
+    let writer = match cli.format {
+        OutputFormat::Text => BoxMakeWriter::new(progress::log_writer),
+        OutputFormat::Json => {
+            progress::disable();
+            BoxMakeWriter::new(std::io::stderr)
+        }
+    };
+
     // Initialize tracing subscriber for logging
     tracing_subscriber::fmt()
         .with_target(false)
         .with_level(true)
+        .with_writer(writer)
         .init();
 
-    let cli = Cli::parse();
+    let mut report = Report::new(cli.cmd.name(), cli.dry_run);
+    let result = run(cli.cmd, cli.dry_run, cli.config, cli.format, &mut report);
+    report.finish(&result);
+    if cli.format == OutputFormat::Json
+        && let Err(err) = report.print()
+    {
+        eprintln!("Error: {err:?}");
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            ExitCode::from(exit_code(&err))
+        }
+    }
+}
 
-    match cli.cmd {
+/// Process exit status, so scripts can tell failures apart:
+///
+/// | code | meaning                                         |
+/// |------|-------------------------------------------------|
+/// | 0    | success                                         |
+/// | 1    | any other error                                 |
+/// | 2    | invalid command line (reported by clap)         |
+/// | 3    | partial success: the plan ran but a push failed |
+/// | 4    | not inside a git repository                     |
+/// | 5    | HEAD is detached                                |
+/// | 6    | missing upstream branch                         |
+/// | 7    | branch diverged from its upstream               |
+/// | 8    | rebase or merge stopped on conflicts            |
+/// | 9    | authentication failed                           |
+/// | 10   | push rejected by the remote                     |
+/// | 11   | uncommitted changes in the way                  |
+fn exit_code(err: &anyhow::Error) -> u8 {
+    if err.downcast_ref::<PartialSuccess>().is_some() {
+        return 3;
+    }
+    match err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<GitxError>())
+    {
+        Some(GitxError::NotARepo { .. }) => 4,
+        Some(GitxError::DetachedHead) => 5,
+        Some(GitxError::MissingUpstream { .. }) => 6,
+        Some(GitxError::Diverged { .. }) => 7,
+        Some(GitxError::Conflict { .. }) => 8,
+        Some(GitxError::AuthFailed { .. }) => 9,
+        Some(GitxError::PushRejected { .. }) => 10,
+        Some(GitxError::DirtyWorktree { .. }) => 11,
+        None => 1,
+    }
+}
+
+fn run(
+    cmd: Cmd,
+    dry_run: bool,
+    config: Option<String>,
+    format: OutputFormat,
+    report: &mut Report,
+) -> anyhow::Result<()> {
+    match cmd {
         Cmd::Sync {
             main,
             push,
             non_interactive,
-        } => commands::sync::run_sync(commands::sync::SyncArgs {
-            dry_run: cli.dry_run,
-            main_override: main,
-            push,
-            non_interactive,
-            config_path: cli.config,
-        }),
+            continue_,
+            skip,
+            abort,
+            plan_out,
+            all,
+            clean,
+            strategy,
+            reset_main,
+        } => commands::sync::run_sync(
+            commands::sync::SyncArgs {
+                dry_run,
+                main_override: main,
+                push,
+                non_interactive,
+                config_path: config,
+                resume: resume_action(continue_, skip, abort),
+                plan_out,
+                all,
+                clean,
+                strategy,
+                reset_main,
+            },
+            report,
+        ),
+        Cmd::Stack { cmd } => {
+            use commands::stack::StackAction;
+            let action = match cmd {
+                StackCmd::Create { name, parent } => StackAction::Create { name, parent },
+                StackCmd::Show => StackAction::Show,
+                StackCmd::SetParent { branch, parent } => StackAction::SetParent { branch, parent },
+                StackCmd::Remove { branch } => StackAction::Remove { branch },
+                StackCmd::Restack => StackAction::Restack,
+            };
+            commands::stack::run_stack(
+                commands::stack::StackArgs {
+                    dry_run,
+                    config_path: config,
+                    action,
+                },
+                report,
+            )
+        }
+        Cmd::Clean { force } => commands::clean::run_clean(
+            commands::clean::CleanArgs {
+                dry_run,
+                config_path: config,
+                force,
+            },
+            report,
+        ),
+        Cmd::Apply { plan } => commands::apply::run_apply(
+            commands::apply::ApplyArgs {
+                dry_run,
+                plan_path: plan,
+                config_path: config,
+            },
+            report,
+        ),
+        Cmd::Config { cmd } => {
+            use commands::config::ConfigAction;
+            let action = match cmd {
+                ConfigCmd::Show => ConfigAction::Show,
+                ConfigCmd::Get { key } => ConfigAction::Get { key },
+                ConfigCmd::Set { key, value, scope } => ConfigAction::Set {
+                    key,
+                    value,
+                    scope: scope.scope(),
+                },
+                ConfigCmd::Init { scope, force } => ConfigAction::Init {
+                    scope: scope.scope(),
+                    force,
+                },
+            };
+            commands::config::run_config(
+                commands::config::ConfigArgs {
+                    dry_run,
+                    config_path: config,
+                    action,
+                    format,
+                },
+                report,
+            )
+        }
+        Cmd::Backups { cmd } => {
+            use commands::backups::BackupsAction;
+            let action = match cmd {
+                BackupsCmd::List { branch } => BackupsAction::List { branch },
+                BackupsCmd::Restore { backup } => BackupsAction::Restore { backup },
+                BackupsCmd::Prune => BackupsAction::Prune,
+            };
+            commands::backups::run_backups(
+                commands::backups::BackupsArgs {
+                    dry_run,
+                    config_path: config,
+                    action,
+                },
+                report,
+            )
+        }
+        Cmd::Undo { run, list, force } => commands::undo::run_undo(
+            commands::undo::UndoArgs {
+                dry_run,
+                run,
+                list,
+                force,
+            },
+            report,
+        ),
+    }
+}
+
+impl Cmd {
+    fn name(&self) -> &'static str {
+        match self {
+            Cmd::Sync { .. } => "sync",
+            Cmd::Clean { .. } => "clean",
+            Cmd::Stack { .. } => "stack",
+            Cmd::Apply { .. } => "apply",
+            Cmd::Config { .. } => "config",
+            Cmd::Backups { .. } => "backups",
+            Cmd::Undo { .. } => "undo",
+        }
+    }
+}
+
+fn resume_action(continue_: bool, skip: bool, abort: bool) -> Option<commands::sync::ResumeAction> {
+    use commands::sync::ResumeAction;
+    match (continue_, skip, abort) {
+        (true, _, _) => Some(ResumeAction::Continue),
+        (_, true, _) => Some(ResumeAction::Skip),
+        (_, _, true) => Some(ResumeAction::Abort),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_exit_code_sees_through_context() {
+        let err = Err::<(), _>(GitxError::DetachedHead)
+            .context("sync needs a branch")
+            .unwrap_err();
+        assert_eq!(exit_code(&err), 5);
+
+        let partial = anyhow::Error::from(PartialSuccess {
+            failed: vec!["push".to_string()],
+        });
+        assert_eq!(exit_code(&partial), 3);
+        assert_eq!(exit_code(&anyhow::anyhow!("boom")), 1);
     }
 }
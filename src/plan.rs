@@ -1,60 +1,398 @@
+use git2::Oid;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
 pub enum SyncOp {
     FetchPrune {
         remote: String,
     },
+    /// Move `branch` to `<remote>/<branch>`; only ever a fast-forward, and
+    /// only to `target`, the remote tip the plan was made against.
     FastForward {
+        remote: String,
         branch: String,
+        #[serde(with = "oid_hex")]
+        target: Oid,
     },
+    /// Replay `src_branch` onto `onto_branch`: the commits after `upstream`
+    /// when the plan found the fork point in the remote's reflog, else the
+    /// commits after the merge base.
     RebaseOnto {
         src_branch: String,
         onto_branch: String,
         non_interactive: bool,
+        #[serde(default, with = "opt_oid", skip_serializing_if = "Option::is_none")]
+        upstream: Option<Oid>,
+    },
+    /// Merge the local branch `from` into `branch` with a merge commit,
+    /// leaving the branch's published history intact.
+    MergeFrom {
+        branch: String,
+        from: String,
+        message: String,
+    },
+    /// Point `branch` at `<remote>/<branch>` although that isn't a
+    /// fast-forward, discarding its local-only commits, provided it still
+    /// points at `tip`. The old tip is saved under a backup ref first.
+    ResetToUpstream {
+        remote: String,
+        branch: String,
+        #[serde(with = "oid_hex")]
+        tip: Oid,
     },
     PushIfFastForward {
         remote: String,
         branch: String,
     },
+    /// Push a rewritten branch, but only if the remote still points at
+    /// `expected` (the remote-tracking tip seen at plan time; `None` means
+    /// the branch must not exist on the remote yet).
+    PushForceWithLease {
+        remote: String,
+        branch: String,
+        #[serde(with = "opt_oid")]
+        expected: Option<Oid>,
+    },
+    /// Replay the commits of `branch` after `base` (its fork point from
+    /// `parent` when planned) onto wherever `parent` points once earlier ops ran.
+    Restack {
+        branch: String,
+        parent: String,
+        #[serde(with = "oid_hex")]
+        base: Oid,
+    },
+    /// Delete a local branch that is no longer needed, provided it still
+    /// points at `tip`.
+    DeleteBranch {
+        branch: String,
+        reason: DeleteReason,
+        #[serde(with = "oid_hex")]
+        tip: Oid,
+    },
+    /// Stash uncommitted changes so the rest of the plan runs on a clean tree.
+    Stash,
+    /// Reapply the changes saved by `Stash`.
+    Unstash,
+}
+
+/// Why a branch is considered safe to delete.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DeleteReason {
+    /// Its remote-tracking branch was pruned.
+    UpstreamGone,
+    /// Its tip is reachable from `into`.
+    Merged { into: String },
+    /// `into` has a commit with the branch's combined change.
+    SquashMerged { into: String },
+}
+
+impl fmt::Display for DeleteReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeleteReason::UpstreamGone => write!(f, "upstream gone"),
+            DeleteReason::Merged { into } => write!(f, "merged into {into}"),
+            DeleteReason::SquashMerged { into } => write!(f, "squash-merged into {into}"),
+        }
+    }
+}
+
+/// A ref value the plan was computed against; `None` means the ref was absent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Precondition {
+    pub reference: String,
+    #[serde(with = "opt_oid")]
+    pub oid: Option<Oid>,
+}
+
+/// A commit that the plan's rebase, restack or merge of `branch` is
+/// expected to stop on, found by replaying the plan in memory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PredictedConflict {
+    pub branch: String,
+    #[serde(with = "oid_hex")]
+    pub commit: Oid,
+    pub summary: String,
+    pub paths: Vec<String>,
 }
 
-#[derive(Debug, Default, Clone)]
+/// A commit the plan's rebase or restack of `branch` drops because `into`
+/// already has the same change; for a reset, a local-only commit `into`
+/// has an equivalent of.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlreadyUpstream {
+    pub branch: String,
+    pub into: String,
+    #[serde(with = "oid_hex")]
+    pub commit: Oid,
+    pub summary: String,
+}
+
+/// A local-only commit that the plan's reset of `branch` throws away, with
+/// no equivalent upstream. Only the backup ref keeps it reachable.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiscardedCommit {
+    pub branch: String,
+    #[serde(with = "oid_hex")]
+    pub commit: Oid,
+    pub summary: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct SyncPlan {
     pub ops: Vec<SyncOp>,
+    /// HEAD at plan time, as a ref name or a detached commit id.
+    #[serde(default)]
+    pub head: Option<String>,
+    #[serde(default)]
+    pub preconditions: Vec<Precondition>,
+    /// Things the plan deliberately leaves alone and the user should know.
+    #[serde(default)]
+    pub notes: Vec<String>,
+    #[serde(default)]
+    pub conflicts: Vec<PredictedConflict>,
+    #[serde(default)]
+    pub already_upstream: Vec<AlreadyUpstream>,
+    #[serde(default)]
+    pub discarded: Vec<DiscardedCommit>,
 }
 
 impl SyncPlan {
     pub fn new() -> Self {
-        Self { ops: vec![] }
+        Self {
+            ops: vec![],
+            head: None,
+            preconditions: vec![],
+            notes: vec![],
+            conflicts: vec![],
+            already_upstream: vec![],
+            discarded: vec![],
+        }
     }
     pub fn push(&mut self, op: SyncOp) {
         self.ops.push(op);
     }
-}
 
-impl fmt::Display for SyncPlan {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    /// Local refs the ops read or move, in first-use order.
+    pub fn touched_refs(&self) -> Vec<String> {
+        let mut refs: Vec<String> = vec![];
         for op in &self.ops {
-            match op {
-                SyncOp::FetchPrune { remote } => writeln!(f, "• fetch --prune {remote}")?,
-                SyncOp::FastForward { branch } => {
-                    writeln!(f, "• fast-forward {branch} from its upstream")?
-                }
+            let names = match op {
+                SyncOp::FastForward { branch, .. }
+                | SyncOp::ResetToUpstream { branch, .. }
+                | SyncOp::DeleteBranch { branch, .. }
+                | SyncOp::PushIfFastForward { branch, .. }
+                | SyncOp::PushForceWithLease { branch, .. } => vec![branch],
                 SyncOp::RebaseOnto {
                     src_branch,
                     onto_branch,
                     ..
-                } => writeln!(f, "• rebase {src_branch} onto {onto_branch}")?,
-                SyncOp::PushIfFastForward { remote, branch } => {
-                    writeln!(f, "• push {branch} -> {remote}/{branch} (ff-only)")?
+                } => vec![src_branch, onto_branch],
+                SyncOp::Restack { branch, parent, .. } => vec![branch, parent],
+                SyncOp::MergeFrom { branch, from, .. } => vec![branch, from],
+                SyncOp::FetchPrune { .. } | SyncOp::Stash | SyncOp::Unstash => vec![],
+            };
+            for name in names {
+                let name = format!("refs/heads/{name}");
+                if !refs.contains(&name) {
+                    refs.push(name);
+                }
+            }
+        }
+        refs
+    }
+
+    /// Describes every way the repository no longer matches the recorded
+    /// preconditions; empty when the plan is still safe to apply.
+    pub fn drift(&self, head: &str, lookup: impl Fn(&str) -> Option<Oid>) -> Vec<String> {
+        let mut drift = vec![];
+        if let Some(expected) = &self.head
+            && expected != head
+        {
+            drift.push(format!("HEAD is {head}, plan expects {expected}"));
+        }
+        for pre in &self.preconditions {
+            let actual = lookup(&pre.reference);
+            if actual != pre.oid {
+                drift.push(format!(
+                    "{} is {}, plan expects {}",
+                    pre.reference,
+                    describe(actual),
+                    describe(pre.oid)
+                ));
+            }
+        }
+        drift
+    }
+}
+
+fn describe(oid: Option<Oid>) -> String {
+    oid.map(|oid| oid.to_string())
+        .unwrap_or_else(|| "absent".to_string())
+}
+
+/// A required oid, stored the same way as the optional ones below.
+mod oid_hex {
+    use git2::Oid;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(oid: &Oid, serializer: S) -> Result<S::Ok, S::Error> {
+        oid.to_string().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Oid, D::Error> {
+        Oid::from_str(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// Oids are stored as hex strings so plan files stay readable.
+mod opt_oid {
+    use git2::Oid;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(oid: &Option<Oid>, serializer: S) -> Result<S::Ok, S::Error> {
+        oid.map(|oid| oid.to_string()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Oid>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|s| Oid::from_str(&s).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+impl fmt::Display for SyncOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncOp::FetchPrune { remote } => write!(f, "fetch --prune {remote}"),
+            SyncOp::FastForward {
+                remote,
+                branch,
+                target,
+            } => write!(
+                f,
+                "fast-forward {branch} to {remote}/{branch} at {}",
+                short(target)
+            ),
+            SyncOp::RebaseOnto {
+                src_branch,
+                onto_branch,
+                upstream,
+                ..
+            } => {
+                write!(f, "rebase {src_branch} onto {onto_branch}")?;
+                if let Some(upstream) = upstream {
+                    write!(f, " (commits after fork point {})", short(upstream))?;
                 }
+                Ok(())
             }
+            SyncOp::MergeFrom { branch, from, .. } => write!(f, "merge {from} into {branch}"),
+            SyncOp::ResetToUpstream {
+                remote,
+                branch,
+                tip,
+            } => write!(
+                f,
+                "reset {branch} to {remote}/{branch}, discarding its local commits (old tip {} kept in a backup ref)",
+                short(tip)
+            ),
+            SyncOp::PushIfFastForward { remote, branch } => {
+                write!(f, "push {branch} -> {remote}/{branch} (ff-only)")
+            }
+            SyncOp::PushForceWithLease {
+                remote,
+                branch,
+                expected,
+            } => {
+                let lease = match expected {
+                    Some(oid) => format!("expects {remote}/{branch} at {}", short(oid)),
+                    None => format!("expects no {remote}/{branch}"),
+                };
+                write!(
+                    f,
+                    "push {branch} -> {remote}/{branch} (force-with-lease, rewrites remote history; {lease})"
+                )
+            }
+            SyncOp::Restack { branch, parent, .. } => write!(f, "restack {branch} onto {parent}"),
+            SyncOp::DeleteBranch {
+                branch,
+                reason,
+                tip,
+            } => {
+                write!(f, "delete branch {branch} at {} ({reason})", short(tip))
+            }
+            SyncOp::Stash => write!(f, "stash uncommitted changes"),
+            SyncOp::Unstash => write!(f, "restore stashed changes"),
+        }
+    }
+}
+
+impl fmt::Display for SyncPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for op in &self.ops {
+            writeln!(f, "• {op}")?;
+            let rewritten = match op {
+                SyncOp::RebaseOnto { src_branch, .. } => Some(src_branch),
+                SyncOp::Restack { branch, .. }
+                | SyncOp::MergeFrom { branch, .. }
+                | SyncOp::ResetToUpstream { branch, .. } => Some(branch),
+                _ => None,
+            };
+            for dropped in self
+                .already_upstream
+                .iter()
+                .filter(|d| Some(&d.branch) == rewritten)
+            {
+                writeln!(
+                    f,
+                    "    ✓ already in {}: {} {}",
+                    dropped.into,
+                    short(&dropped.commit),
+                    dropped.summary
+                )?;
+            }
+            for discarded in self
+                .discarded
+                .iter()
+                .filter(|d| Some(&d.branch) == rewritten)
+            {
+                writeln!(
+                    f,
+                    "    ✗ discards {} {}",
+                    short(&discarded.commit),
+                    discarded.summary
+                )?;
+            }
+            for conflict in self
+                .conflicts
+                .iter()
+                .filter(|c| Some(&c.branch) == rewritten)
+            {
+                writeln!(
+                    f,
+                    "    ⚠ will conflict at {} {}: {}",
+                    short(&conflict.commit),
+                    conflict.summary,
+                    conflict.paths.join(", ")
+                )?;
+            }
+        }
+        for note in &self.notes {
+            writeln!(f, "! {note}")?;
         }
         Ok(())
     }
 }
 
+/// Abbreviated hex form of `oid` for plan output.
+pub(crate) fn short(oid: &Oid) -> String {
+    oid.to_string()[..7].to_string()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -88,10 +426,12 @@ mod tests {
     fn test_sync_plan_display_fast_forward() {
         let mut plan = SyncPlan::new();
         plan.push(SyncOp::FastForward {
+            remote: "origin".to_string(),
             branch: "main".to_string(),
+            target: Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap(),
         });
         let output = format!("{}", plan);
-        assert_eq!(output, "• fast-forward main from its upstream\n");
+        assert_eq!(output, "• fast-forward main to origin/main at 0123456\n");
     }
 
     #[test]
@@ -101,6 +441,7 @@ mod tests {
             src_branch: "feature".to_string(),
             onto_branch: "main".to_string(),
             non_interactive: false,
+            upstream: None,
         });
         let output = format!("{}", plan);
         assert_eq!(output, "• rebase feature onto main\n");
@@ -117,6 +458,168 @@ mod tests {
         assert_eq!(output, "• push feature -> origin/feature (ff-only)\n");
     }
 
+    #[test]
+    fn test_sync_plan_display_push_force_with_lease() {
+        let mut plan = SyncPlan::new();
+        plan.push(SyncOp::PushForceWithLease {
+            remote: "origin".to_string(),
+            branch: "feature".to_string(),
+            expected: Some(Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap()),
+        });
+        plan.push(SyncOp::PushForceWithLease {
+            remote: "origin".to_string(),
+            branch: "new".to_string(),
+            expected: None,
+        });
+        let output = format!("{}", plan);
+        assert_eq!(
+            output,
+            "• push feature -> origin/feature (force-with-lease, rewrites remote history; expects origin/feature at 0123456)\n\
+             • push new -> origin/new (force-with-lease, rewrites remote history; expects no origin/new)\n"
+        );
+    }
+
+    #[test]
+    fn test_sync_plan_display_delete_branch() {
+        let tip = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let mut plan = SyncPlan::new();
+        for (branch, reason) in [
+            ("gone", DeleteReason::UpstreamGone),
+            (
+                "done",
+                DeleteReason::Merged {
+                    into: "main".to_string(),
+                },
+            ),
+            (
+                "squashed",
+                DeleteReason::SquashMerged {
+                    into: "main".to_string(),
+                },
+            ),
+        ] {
+            plan.push(SyncOp::DeleteBranch {
+                branch: branch.to_string(),
+                reason,
+                tip,
+            });
+        }
+        let output = format!("{}", plan);
+        assert_eq!(
+            output,
+            "• delete branch gone at 0123456 (upstream gone)\n\
+             • delete branch done at 0123456 (merged into main)\n\
+             • delete branch squashed at 0123456 (squash-merged into main)\n"
+        );
+    }
+
+    #[test]
+    fn test_sync_plan_display_restack() {
+        let mut plan = SyncPlan::new();
+        plan.push(SyncOp::Restack {
+            branch: "feature-b".to_string(),
+            parent: "feature-a".to_string(),
+            base: Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap(),
+        });
+        let output = format!("{}", plan);
+        assert_eq!(output, "• restack feature-b onto feature-a\n");
+    }
+
+    #[test]
+    fn test_sync_plan_display_stash() {
+        let mut plan = SyncPlan::new();
+        plan.push(SyncOp::Stash);
+        plan.push(SyncOp::Unstash);
+        let output = format!("{}", plan);
+        assert_eq!(
+            output,
+            "• stash uncommitted changes\n• restore stashed changes\n"
+        );
+    }
+
+    #[test]
+    fn test_sync_plan_display_notes() {
+        let mut plan = SyncPlan::new();
+        plan.push(SyncOp::FetchPrune {
+            remote: "origin".to_string(),
+        });
+        plan.notes
+            .push("old: upstream origin/old is gone".to_string());
+        let output = format!("{}", plan);
+        assert_eq!(
+            output,
+            "• fetch --prune origin\n! old: upstream origin/old is gone\n"
+        );
+    }
+
+    #[test]
+    fn test_sync_plan_json_round_trip() {
+        let oid = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let mut plan = SyncPlan::new();
+        plan.push(SyncOp::PushForceWithLease {
+            remote: "origin".to_string(),
+            branch: "feature".to_string(),
+            expected: Some(oid),
+        });
+        plan.preconditions.push(Precondition {
+            reference: "refs/heads/feature".to_string(),
+            oid: None,
+        });
+
+        let json = serde_json::to_string(&plan).unwrap();
+        assert!(json.contains(r#""op":"push_force_with_lease""#));
+        assert!(json.contains(r#""expected":"0123456789abcdef0123456789abcdef01234567""#));
+
+        let parsed: SyncPlan = serde_json::from_str(&json).unwrap();
+        assert_eq!(format!("{parsed}"), format!("{plan}"));
+        assert_eq!(parsed.preconditions, plan.preconditions);
+    }
+
+    #[test]
+    fn test_sync_plan_touched_refs() {
+        let mut plan = SyncPlan::new();
+        plan.push(SyncOp::FetchPrune {
+            remote: "origin".to_string(),
+        });
+        plan.push(SyncOp::FastForward {
+            remote: "origin".to_string(),
+            branch: "main".to_string(),
+            target: Oid::zero(),
+        });
+        plan.push(SyncOp::RebaseOnto {
+            src_branch: "feature".to_string(),
+            onto_branch: "main".to_string(),
+            non_interactive: true,
+            upstream: None,
+        });
+        assert_eq!(
+            plan.touched_refs(),
+            vec!["refs/heads/main", "refs/heads/feature"]
+        );
+    }
+
+    #[test]
+    fn test_sync_plan_drift() {
+        let oid = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let mut plan = SyncPlan::new();
+        plan.head = Some("refs/heads/feature".to_string());
+        plan.preconditions.push(Precondition {
+            reference: "refs/heads/main".to_string(),
+            oid: Some(oid),
+        });
+
+        assert!(plan.drift("refs/heads/feature", |_| Some(oid)).is_empty());
+
+        let drift = plan.drift("refs/heads/main", |_| None);
+        assert_eq!(
+            drift,
+            vec![
+                "HEAD is refs/heads/main, plan expects refs/heads/feature".to_string(),
+                format!("refs/heads/main is absent, plan expects {oid}"),
+            ]
+        );
+    }
+
     #[test]
     fn test_sync_plan_display_multiple_ops() {
         let mut plan = SyncPlan::new();
@@ -124,19 +627,70 @@ mod tests {
             remote: "origin".to_string(),
         });
         plan.push(SyncOp::FastForward {
+            remote: "origin".to_string(),
             branch: "main".to_string(),
+            target: Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap(),
         });
         plan.push(SyncOp::RebaseOnto {
             src_branch: "feature".to_string(),
             onto_branch: "main".to_string(),
             non_interactive: true,
+            upstream: None,
         });
         let output = format!("{}", plan);
         assert_eq!(
             output,
             "• fetch --prune origin\n\
-             • fast-forward main from its upstream\n\
+             • fast-forward main to origin/main at 0123456\n\
              • rebase feature onto main\n"
         );
     }
+
+    #[test]
+    fn test_sync_plan_display_predicted_conflicts() {
+        let mut plan = SyncPlan::new();
+        plan.push(SyncOp::RebaseOnto {
+            src_branch: "feature".to_string(),
+            onto_branch: "main".to_string(),
+            non_interactive: false,
+            upstream: None,
+        });
+        plan.conflicts.push(PredictedConflict {
+            branch: "feature".to_string(),
+            commit: Oid::from_str("1234567890abcdef1234567890abcdef12345678").unwrap(),
+            summary: "touch config".to_string(),
+            paths: vec!["a.rs".to_string(), "b.rs".to_string()],
+        });
+        assert_eq!(
+            format!("{plan}"),
+            "• rebase feature onto main\n    ⚠ will conflict at 1234567 touch config: a.rs, b.rs\n"
+        );
+    }
+
+    #[test]
+    fn test_sync_plan_display_reset_to_upstream() {
+        let mut plan = SyncPlan::new();
+        plan.push(SyncOp::ResetToUpstream {
+            remote: "origin".to_string(),
+            branch: "main".to_string(),
+            tip: Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap(),
+        });
+        plan.already_upstream.push(AlreadyUpstream {
+            branch: "main".to_string(),
+            into: "origin/main".to_string(),
+            commit: Oid::from_str("1234567890abcdef1234567890abcdef12345678").unwrap(),
+            summary: "rewritten upstream".to_string(),
+        });
+        plan.discarded.push(DiscardedCommit {
+            branch: "main".to_string(),
+            commit: Oid::from_str("abcdef0123456789abcdef0123456789abcdef01").unwrap(),
+            summary: "local only".to_string(),
+        });
+        assert_eq!(
+            format!("{plan}"),
+            "• reset main to origin/main, discarding its local commits (old tip 0123456 kept in a backup ref)\n    \
+             ✓ already in origin/main: 1234567 rewritten upstream\n    \
+             ✗ discards abcdef0 local only\n"
+        );
+    }
 }
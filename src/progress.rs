@@ -0,0 +1,118 @@
+use crate::plan::SyncPlan;
+use crate::report::OpStatus;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::{IsTerminal, Write};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+/// Every bar is drawn through this, and hidden when stdout is not a TTY so
+/// piped output and CI logs only get the plain log lines.
+static MULTI: LazyLock<MultiProgress> =
+    LazyLock::new(|| MultiProgress::with_draw_target(draw_target(std::io::stdout().is_terminal())));
+
+fn draw_target(stdout_is_terminal: bool) -> ProgressDrawTarget {
+    if stdout_is_terminal {
+        ProgressDrawTarget::stderr()
+    } else {
+        ProgressDrawTarget::hidden()
+    }
+}
+
+/// Hides all progress, e.g. while stdout carries a JSON document.
+pub fn disable() {
+    MULTI.set_draw_target(ProgressDrawTarget::hidden());
+}
+
+/// Log sink for `tracing_subscriber` that moves the bars out of the way
+/// while a line is written, so logs and progress don't overwrite each other.
+pub fn log_writer() -> LogWriter {
+    LogWriter(vec![])
+}
+
+pub struct LogWriter(Vec<u8>);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        let line = std::mem::take(&mut self.0);
+        MULTI.suspend(|| std::io::stdout().write_all(&line))
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// One line per op of a plan, showing whether it is pending, running,
+/// done, skipped or failed.
+pub struct PlanProgress {
+    bars: Vec<ProgressBar>,
+    labels: Vec<String>,
+}
+
+impl PlanProgress {
+    pub fn new(plan: &SyncPlan) -> Self {
+        let labels: Vec<String> = plan.ops.iter().map(ToString::to_string).collect();
+        let bars = labels
+            .iter()
+            .map(|label| {
+                let bar = MULTI.add(ProgressBar::new_spinner());
+                bar.set_style(status_style());
+                bar.set_prefix("·");
+                bar.set_message(label.clone());
+                bar
+            })
+            .collect();
+        Self { bars, labels }
+    }
+
+    /// Marks op `index` as running and returns its line, which transfers
+    /// use to report their progress.
+    pub fn start(&self, index: usize) -> ProgressBar {
+        let bar = &self.bars[index];
+        bar.set_style(
+            ProgressStyle::with_template("{spinner} {msg}").expect("valid progress template"),
+        );
+        bar.enable_steady_tick(Duration::from_millis(100));
+        bar.clone()
+    }
+
+    pub fn finish(&self, index: usize, status: &OpStatus) {
+        let bar = &self.bars[index];
+        bar.set_style(status_style());
+        bar.set_prefix(match status {
+            OpStatus::Success => "✓",
+            OpStatus::Skipped { .. } => "–",
+            OpStatus::Failed { .. } => "✗",
+        });
+        // Drop any transfer details and go back to the op itself.
+        bar.finish_with_message(self.labels[index].clone());
+    }
+}
+
+fn status_style() -> ProgressStyle {
+    ProgressStyle::with_template("{prefix} {msg}").expect("valid progress template")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bars_are_hidden_when_stdout_is_not_a_terminal() {
+        assert!(draw_target(false).is_hidden());
+
+        let multi = MultiProgress::with_draw_target(draw_target(false));
+        let bar = multi.add(ProgressBar::new_spinner());
+        assert!(bar.is_hidden());
+    }
+}
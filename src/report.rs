@@ -0,0 +1,207 @@
+use crate::config::ResolvedConfig;
+use crate::gitx::{Backup, GitRepo, OpenRepoOpts};
+use crate::journal::Journal;
+use crate::plan::{SyncOp, SyncPlan};
+use crate::stack::Stack;
+use anyhow::Result;
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Log lines for people.
+    Text,
+    /// A single JSON document on stdout; logs go to stderr.
+    Json,
+}
+
+/// Everything a command did, printed as one document with `--format json`.
+/// Commands fill in the parts that apply to them.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub command: String,
+    pub dry_run: bool,
+    pub config: Option<ResolvedConfig>,
+    pub plan: Option<SyncPlan>,
+    pub results: Vec<OpResult>,
+    /// The setting `config get` looked up.
+    pub setting: Option<Setting>,
+    /// Parent links, from `stack show`.
+    pub stack: Option<Stack>,
+    /// Backups `backups list` found, or `backups prune` deleted.
+    pub backups: Option<Vec<BackupState>>,
+    /// The run `undo` undid (or would undo), or every run with `--list`.
+    pub runs: Option<Vec<Journal>>,
+    /// Local branches once the command finished.
+    pub branches: Vec<BranchState>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Setting {
+    pub key: String,
+    pub value: toml::Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackupState {
+    pub name: String,
+    pub branch: String,
+    /// When it was taken, in seconds since the Unix epoch.
+    pub time: u64,
+    pub tip: String,
+}
+
+impl From<&Backup> for BackupState {
+    fn from(backup: &Backup) -> Self {
+        Self {
+            name: backup.name.clone(),
+            branch: backup.branch.clone(),
+            time: backup.time,
+            tip: backup.tip.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpResult {
+    pub op: SyncOp,
+    #[serde(flatten)]
+    pub status: OpStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum OpStatus {
+    Success,
+    /// The op ran but deliberately left things alone, or never ran.
+    Skipped {
+        reason: String,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+/// The plan ran to the end, but ops that don't stop it (pushes) failed.
+#[derive(Debug)]
+pub struct PartialSuccess {
+    pub failed: Vec<String>,
+}
+
+impl fmt::Display for PartialSuccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sync finished, but these ops failed: {}",
+            self.failed.join("; ")
+        )
+    }
+}
+
+impl std::error::Error for PartialSuccess {}
+
+#[derive(Debug, Serialize)]
+pub struct BranchState {
+    pub name: String,
+    pub tip: String,
+    pub current: bool,
+}
+
+impl Report {
+    pub fn new(command: &str, dry_run: bool) -> Self {
+        Self {
+            command: command.to_string(),
+            dry_run,
+            ..Self::default()
+        }
+    }
+
+    pub fn record(&mut self, op: &SyncOp, status: OpStatus) {
+        self.results.push(OpResult {
+            op: op.clone(),
+            status,
+        });
+    }
+
+    /// Records how the command ended and where the branches were left.
+    pub fn finish(&mut self, result: &Result<()>) {
+        if let Err(err) = result {
+            self.error = Some(format!("{err:#}"));
+        }
+        if let Ok(repo) = GitRepo::discover(OpenRepoOpts {
+            workdir: ".".into(),
+        }) {
+            self.branches = branch_states(&repo).unwrap_or_default();
+        }
+    }
+
+    pub fn print(&self) -> Result<()> {
+        println!("{}", serde_json::to_string_pretty(self)?);
+        Ok(())
+    }
+}
+
+fn branch_states(repo: &GitRepo) -> Result<Vec<BranchState>> {
+    let current = repo.current_branch_name().ok();
+    Ok(repo
+        .local_branch_tips()?
+        .into_iter()
+        .filter_map(|(refname, tip)| {
+            let name = refname.strip_prefix("refs/heads/")?.to_string();
+            Some(BranchState {
+                current: current.as_deref() == Some(name.as_str()),
+                name,
+                tip: tip.to_string(),
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_json_shape() {
+        let mut report = Report::new("sync", false);
+        let op = SyncOp::PushIfFastForward {
+            remote: "origin".to_string(),
+            branch: "feature".to_string(),
+        };
+        report.record(&op, OpStatus::Success);
+        report.record(
+            &op,
+            OpStatus::Skipped {
+                reason: "rejected".to_string(),
+            },
+        );
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["command"], "sync");
+        assert_eq!(json["results"][0]["status"], "success");
+        assert_eq!(json["results"][0]["op"]["op"], "push_if_fast_forward");
+        assert_eq!(json["results"][1]["status"], "skipped");
+        assert_eq!(json["results"][1]["reason"], "rejected");
+        assert!(json["error"].is_null());
+    }
+
+    #[test]
+    fn test_report_json_command_results() {
+        let mut report = Report::new("config", false);
+        report.setting = Some(Setting {
+            key: "backup.max_count".to_string(),
+            value: 20.into(),
+        });
+        let mut stack = Stack::default();
+        stack.set_parent("b", "a").unwrap();
+        report.stack = Some(stack);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["setting"]["key"], "backup.max_count");
+        assert_eq!(json["setting"]["value"], 20);
+        assert_eq!(json["stack"]["parents"]["b"], "a");
+        assert!(json["backups"].is_null());
+        assert!(json["runs"].is_null());
+    }
+}
@@ -0,0 +1,159 @@
+use crate::gitx::GitRepo;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Parent relationships between stacked branches, kept in
+/// `.git/git-helper/stack.toml`. A branch without an entry is a root.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Stack {
+    #[serde(default)]
+    pub parents: BTreeMap<String, String>,
+}
+
+impl Stack {
+    pub fn path(repo: &GitRepo) -> PathBuf {
+        repo.state_dir().join("stack.toml")
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("parse {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, toml::to_string(self)?)
+            .with_context(|| format!("write {}", path.display()))
+    }
+
+    pub fn parent(&self, branch: &str) -> Option<&str> {
+        self.parents.get(branch).map(String::as_str)
+    }
+
+    /// Records `parent` as the base of `branch`, refusing to create a cycle.
+    pub fn set_parent(&mut self, branch: &str, parent: &str) -> Result<()> {
+        if branch == parent || self.ancestors(parent).iter().any(|a| a == branch) {
+            anyhow::bail!("{parent} is stacked on {branch}; that would make a cycle");
+        }
+        self.parents.insert(branch.to_string(), parent.to_string());
+        Ok(())
+    }
+
+    /// Forgets `branch`, re-parenting its children onto its own parent.
+    pub fn remove(&mut self, branch: &str) {
+        let parent = self.parents.remove(branch);
+        for child in self.children(branch) {
+            match &parent {
+                Some(parent) => self.parents.insert(child, parent.clone()),
+                None => self.parents.remove(&child),
+            };
+        }
+    }
+
+    pub fn children(&self, branch: &str) -> Vec<String> {
+        self.parents
+            .iter()
+            .filter(|(_, parent)| *parent == branch)
+            .map(|(child, _)| child.clone())
+            .collect()
+    }
+
+    /// Parent, grandparent, ... of `branch`, nearest first.
+    pub fn ancestors(&self, branch: &str) -> Vec<String> {
+        let mut chain = vec![];
+        let mut cursor = branch;
+        while let Some(parent) = self.parent(cursor) {
+            if chain.iter().any(|b| b == parent) {
+                break;
+            }
+            chain.push(parent.to_string());
+            cursor = parent;
+        }
+        chain
+    }
+
+    /// Every branch stacked (directly or not) on `branch`, parents before
+    /// their children.
+    pub fn descendants(&self, branch: &str) -> Vec<String> {
+        let mut order = vec![];
+        let mut queue = self.children(branch);
+        while !queue.is_empty() {
+            let next = queue.remove(0);
+            if order.contains(&next) {
+                continue;
+            }
+            queue.extend(self.children(&next));
+            order.push(next);
+        }
+        order
+    }
+
+    /// Branches that have children but no recorded parent themselves.
+    pub fn roots(&self) -> Vec<String> {
+        let mut roots: Vec<String> = self
+            .parents
+            .values()
+            .filter(|parent| !self.parents.contains_key(*parent))
+            .cloned()
+            .collect();
+        roots.sort();
+        roots.dedup();
+        roots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack(pairs: &[(&str, &str)]) -> Stack {
+        let mut stack = Stack::default();
+        for (branch, parent) in pairs {
+            stack.set_parent(branch, parent).unwrap();
+        }
+        stack
+    }
+
+    #[test]
+    fn test_descendants_are_topological() {
+        let stack = stack(&[("a", "main"), ("b", "a"), ("c", "b"), ("d", "a")]);
+        assert_eq!(stack.descendants("a"), vec!["b", "d", "c"]);
+        assert_eq!(stack.descendants("main"), vec!["a", "b", "d", "c"]);
+        assert_eq!(stack.ancestors("c"), vec!["b", "a", "main"]);
+        assert_eq!(stack.roots(), vec!["main"]);
+    }
+
+    #[test]
+    fn test_set_parent_rejects_cycles() {
+        let mut stack = stack(&[("a", "main"), ("b", "a")]);
+        assert!(stack.set_parent("a", "b").is_err());
+        assert!(stack.set_parent("a", "a").is_err());
+    }
+
+    #[test]
+    fn test_remove_reparents_children() {
+        let mut stack = stack(&[("a", "main"), ("b", "a"), ("c", "b")]);
+        stack.remove("b");
+        assert_eq!(stack.parent("c"), Some("a"));
+        assert_eq!(stack.parent("b"), None);
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("git-helper").join("stack.toml");
+        assert!(Stack::load(&path)?.parents.is_empty());
+
+        stack(&[("a", "main")]).save(&path)?;
+        assert_eq!(Stack::load(&path)?.parent("a"), Some("main"));
+        Ok(())
+    }
+}